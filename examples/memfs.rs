@@ -3,7 +3,7 @@ use simply_fuse::basic::*;
 use simply_fuse::error::{FSError, FSResult as Result};
 use simply_fuse::*;
 
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::io::BufRead;
 use std::path::Path;
 
@@ -26,7 +26,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     fs.inodes
         .push_entry(1u64.into(), "root2".into(), Directory::default());
 
-    fs.inodes.push_entry(
+    fs.inodes.push_file(
         ROOT_INODE,
         "file".into(),
         File::new(TEST_MSG.as_bytes().into()),
@@ -34,7 +34,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut r = Runner::new(fs, mount);
     println!("{:#?}", r);
-    r.run_block()?;
+    r.run_block_with_signals()?;
 
     Ok(())
 }
@@ -43,6 +43,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 pub struct File {
     pub data: Vec<u8>,
     pub attrs: FileAttributes,
+    pub xattrs: XAttrStore,
 }
 
 impl File {
@@ -51,9 +52,11 @@ impl File {
             attrs: FileAttributes::builder()
                 .size(data.len() as u64)
                 .mode(libc::S_IFREG | 0o755)
+                .nlink(1u32)
                 .build(),
 
             data,
+            xattrs: XAttrStore::default(),
         }
     }
 
@@ -68,6 +71,12 @@ impl Attributable for File {
     }
 }
 
+impl AttributableMut for File {
+    fn setattrs(&mut self, attrs: FileAttributes) {
+        self.attrs = attrs;
+    }
+}
+
 impl Filelike for File {}
 
 #[derive(Debug)]
@@ -81,9 +90,21 @@ impl MemFS {
             inodes: INodeTable::default(),
         }
     }
+
+    fn file(&self, ino: INode) -> Result<&File> {
+        let entry = self.inodes.get(ino).ok_or(FSError::NoEntry)?;
+        entry.as_file().ok_or(FSError::NotFile)
+    }
+
+    fn file_mut(&mut self, ino: INode) -> Result<&mut File> {
+        let entry = self.inodes.get_mut(ino).ok_or(FSError::NoEntry)?;
+        entry.as_file_mut().ok_or(FSError::NotFile)
+    }
 }
 
 impl Filesystem for MemFS {
+    type Error = FSError;
+
     fn lookup(&mut self, parent: INode, name: &OsStr) -> Result<Lookup> {
         let parent = self
             .inodes
@@ -162,7 +183,13 @@ impl Filesystem for MemFS {
         Ok(content)
     }
 
-    fn write<T: BufRead>(&mut self, ino: INode, offset: u64, size: u32, mut buf: T) -> Result<u32> {
+    fn write<T: BufRead>(
+        &mut self,
+        ino: INode,
+        offset: u64,
+        size: u32,
+        mut buf: T,
+    ) -> Result<WriteResult> {
         let file = self.inodes.get_mut(ino).ok_or(FSError::NoEntry)?;
         let file = file.as_file_mut().ok_or(FSError::NotFile)?;
 
@@ -177,17 +204,196 @@ impl Filesystem for MemFS {
 
         file.attrs.set_size((offset + size) as u64);
 
-        Ok(size as u32)
+        Ok((size as u32).into())
+    }
+
+    fn copy_file_range(
+        &mut self,
+        src_ino: INode,
+        _src_fh: Filehandle,
+        src_offset: u64,
+        dst_ino: INode,
+        _dst_fh: Filehandle,
+        dst_offset: u64,
+        len: u64,
+        _flags: u64,
+    ) -> Result<u64> {
+        let src_len = self.file(src_ino)?.data.len();
+        let src = (src_offset as usize).min(src_len);
+        let len = (len as usize).min(src_len - src);
+        let dst = dst_offset as usize;
+
+        let data = if src_ino == dst_ino {
+            None
+        } else {
+            Some(self.file(src_ino)?.data[src..src + len].to_vec())
+        };
+
+        let file = self.file_mut(dst_ino)?;
+
+        if dst + len > file.data.len() {
+            file.data.resize(dst + len, 0);
+            file.attrs.set_size((dst + len) as u64);
+        }
+
+        match data {
+            Some(data) => file.data[dst..dst + len].copy_from_slice(&data),
+            None => file.data.copy_within(src..src + len, dst),
+        }
+
+        Ok(len as u64)
+    }
+
+    fn fallocate(
+        &mut self,
+        ino: INode,
+        _fh: Filehandle,
+        offset: u64,
+        length: u64,
+        mode: FallocateMode,
+    ) -> Result<()> {
+        if mode.contains(FallocateMode::COLLAPSE_RANGE) {
+            return Err(FSError::NotSupported);
+        }
+
+        let file = self.file_mut(ino)?;
+        // a range past the end of the address space could never be allocated
+        let end = offset
+            .checked_add(length)
+            .and_then(|end| usize::try_from(end).ok())
+            .ok_or(FSError::NoSpace)?;
+        let start = offset as usize;
+
+        if !mode.contains(FallocateMode::KEEP_SIZE) && end > file.data.len() {
+            file.data.resize(end, 0);
+            file.attrs.set_size(end as u64);
+        }
+
+        if mode.contains(FallocateMode::PUNCH_HOLE) || mode.contains(FallocateMode::ZERO_RANGE) {
+            let end = end.min(file.data.len());
+
+            if start < end {
+                file.data[start..end].fill(0);
+            }
+        }
+
+        Ok(())
     }
 
     fn setattr(&mut self, ino: INode, attrs: SetFileAttributes) -> Result<FileAttributes> {
         let entry = self.inodes.get_mut(ino).ok_or(FSError::NoEntry)?;
-
-        match entry.kind_mut() {
-            INodeKind::Directory(dir) => dir.apply_attrs(attrs),
-            INodeKind::File(file) => file.attrs.apply_attrs(attrs),
-        };
+        entry.apply_attrs(attrs);
 
         Ok(entry.getattrs())
     }
+
+    fn setxattr(
+        &mut self,
+        ino: INode,
+        attr_name: &OsStr,
+        attr_value: &[u8],
+        flags: SetXAttrFlags,
+    ) -> Result<()> {
+        self.file_mut(ino)?.xattrs.set(attr_name, attr_value, flags)
+    }
+
+    fn getxattr(&mut self, ino: INode, attr_name: &OsStr, max_len: u32) -> Result<XAttrRef<'_>> {
+        self.file(ino)?.xattrs.get_ref(attr_name, max_len)
+    }
+
+    fn listxattrs(&mut self, ino: INode, max_len: u32) -> Result<(OsString, u32)> {
+        self.file(ino)?.xattrs.list(max_len)
+    }
+
+    fn make_dir(&mut self, parent: INode, name: &OsStr, mode: u32) -> Result<Lookup> {
+        if self.lookup(parent, name).is_ok() {
+            return Err(FSError::AlreadyExists);
+        }
+
+        let attrs = SetFileAttributes::builder()
+            .mode(Some(libc::S_IFDIR | (mode & 0o7777)))
+            .build();
+
+        let ino = self
+            .inodes
+            .push_entry_with_attrs(parent, name.into(), Directory::default(), attrs)
+            .ok_or(FSError::NotDirectory)?;
+
+        Ok(Lookup::builder()
+            .attributes(self.getattr(ino)?)
+            .inode(ino)
+            .build())
+    }
+
+    fn make_symlink(&mut self, parent: INode, name: &OsStr, link: &OsStr) -> Result<Lookup> {
+        if self.lookup(parent, name).is_ok() {
+            return Err(FSError::AlreadyExists);
+        }
+
+        let ino = self
+            .inodes
+            .push_symlink(parent, name.into(), link.into())
+            .ok_or(FSError::NotDirectory)?;
+
+        Ok(Lookup::builder()
+            .attributes(self.getattr(ino)?)
+            .inode(ino)
+            .build())
+    }
+
+    fn readlink(&mut self, ino: INode) -> Result<&OsStr> {
+        let entry = self.inodes.get(ino).ok_or(FSError::NoEntry)?;
+        let link = entry.as_symlink().ok_or(FSError::InvalidArgument)?;
+
+        Ok(link.target())
+    }
+
+    fn link(&mut self, ino: INode, new_parent: INode, new_name: &OsStr) -> Result<Lookup> {
+        self.inodes.link_entry(ino, new_parent, new_name)?;
+
+        Ok(Lookup::builder()
+            .attributes(self.getattr(ino)?)
+            .inode(ino)
+            .build())
+    }
+
+    fn unlink(&mut self, parent: INode, name: &OsStr) -> Result<()> {
+        self.inodes.unlink_file(parent, name).map(|_| ())
+    }
+
+    fn rmdir(&mut self, parent: INode, name: &OsStr) -> Result<()> {
+        let dir = self.inodes.get(parent).ok_or(FSError::NoEntry)?;
+        let dir = dir.as_dir().ok_or(FSError::NotDirectory)?;
+        let ino = *dir.get(name).ok_or(FSError::NoEntry)?;
+
+        if self.inodes.get(ino).and_then(|x| x.as_dir()).is_none() {
+            return Err(FSError::NotDirectory);
+        }
+
+        self.inodes.remove(parent, name).map(|_| ())
+    }
+
+    fn rename(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        new_parent: INode,
+        new_name: &OsStr,
+        flags: RenameFlags,
+    ) -> Result<()> {
+        match flags {
+            RenameFlags::Replace => (),
+            RenameFlags::NoReplace => {
+                let new_dir = self.inodes.get(new_parent).ok_or(FSError::NoEntry)?;
+                let new_dir = new_dir.as_dir().ok_or(FSError::NotDirectory)?;
+
+                if new_dir.get(new_name).is_some() {
+                    return Err(FSError::AlreadyExists);
+                }
+            }
+            RenameFlags::Exchange => return Err(FSError::NotSupported),
+        }
+
+        self.inodes.rename(parent, name, new_parent, new_name)
+    }
 }
@@ -0,0 +1,3 @@
+//! Bridges between blocking and asynchronous filesystems. Enabled by the `tokio` feature.
+
+pub use crate::async_runner::BlockingAdapter;
@@ -0,0 +1,862 @@
+//! An asynchronous counterpart to `Runner`, for filesystems which need to await I/O. Enabled by
+//! the `tokio` feature.
+//!
+//! Every request is handled in its own task, so operations run concurrently and
+//! `AsyncFilesystem` methods take `&self`. Only the most common operations are covered so far;
+//! everything else is answered with `ENOSYS`.
+//!
+//! ```no_run
+//! use simply_fuse::async_runner::{AsyncFilesystem, AsyncRunner};
+//! use simply_fuse::attrs::FileAttributes;
+//! use simply_fuse::error::{FSError, FSResult};
+//! use simply_fuse::INode;
+//!
+//! struct EmptyFS;
+//!
+//! impl AsyncFilesystem for EmptyFS {
+//!     type Error = FSError;
+//!
+//!     async fn getattr(&self, _ino: INode) -> FSResult<FileAttributes> {
+//!         Ok(FileAttributes::builder().mode(libc::S_IFDIR | 0o755).build())
+//!     }
+//! }
+//!
+//! # async fn run() -> simply_fuse::error::Result<()> {
+//! AsyncRunner::new(EmptyFS, "/mnt/empty").run().await
+//! # }
+//! ```
+
+use crate::attrs::{FileAttributes, SetFileAttributes};
+use crate::error::{Error, FSError, FSResult, PolyfuseError, Result};
+use crate::runner::{apply_capabilities, set_file_attributes};
+use crate::{
+    DirEntry, Filehandle, Filesystem, INode, InitCapabilities, Lookup, OpKind, OpenDir, OpenFile,
+    RenameFlags, RunnerConfig, StatfsReply, TimeoutPolicy, WriteResult,
+};
+
+use std::ffi::OsStr;
+use std::future::Future;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use polyfuse::{op, reply, KernelConfig, Notifier, Operation, Request, Session};
+use tokio::task::{self, JoinSet};
+use tokio::time;
+use tracing::{error, warn};
+
+/// Mirrors `Filesystem`, with every operation returning a future. Defaults match those of
+/// `Filesystem`.
+pub trait AsyncFilesystem: Send + Sync + 'static {
+    /// See `Filesystem::Error`
+    type Error: Into<FSError> + From<FSError> + Send;
+
+    /// See `Filesystem::init`. Called before mounting, outside of any task.
+    fn init(&self, capabilities: InitCapabilities) -> FSResult<InitCapabilities, Self::Error> {
+        Ok(capabilities)
+    }
+
+    /// Whether an operation of `kind` may be dropped when it runs past its `TimeoutPolicy`
+    /// deadline. Dropping the future has to actually stop the operation, since the kernel is
+    /// told it failed; return false for any whose effects would still land afterwards.
+    fn can_time_out(&self, _kind: OpKind) -> bool {
+        true
+    }
+
+    /// See `Filesystem::destroy`. Called once every in-flight operation has finished.
+    fn destroy(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// See `Filesystem::forget`
+    fn forget(&self, _ino: INode, _nlookup: u64) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    fn lookup(
+        &self,
+        _parent: INode,
+        _name: &OsStr,
+    ) -> impl Future<Output = FSResult<Lookup, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn getattr(
+        &self,
+        _ino: INode,
+    ) -> impl Future<Output = FSResult<FileAttributes, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn setattr(
+        &self,
+        _ino: INode,
+        _attr: SetFileAttributes,
+    ) -> impl Future<Output = FSResult<FileAttributes, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn open(
+        &self,
+        _ino: INode,
+        _flags: u32,
+    ) -> impl Future<Output = FSResult<OpenFile, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn release(
+        &self,
+        _ino: INode,
+        _fh: Filehandle,
+        _flags: u32,
+        _flush: bool,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    fn open_dir(
+        &self,
+        _ino: INode,
+        _flags: u32,
+    ) -> impl Future<Output = FSResult<OpenDir, Self::Error>> + Send {
+        async { Ok(OpenDir::builder().handle(Filehandle::from_raw(0)).build()) }
+    }
+
+    fn releasedir(
+        &self,
+        _ino: INode,
+        _fh: Filehandle,
+        _flags: u32,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+
+    /// See `Filesystem::readdir` for what the entries must look like
+    fn readdir(
+        &self,
+        _dir: INode,
+        _offset: u64,
+    ) -> impl Future<Output = FSResult<Vec<DirEntry>, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    /// Unlike `Filesystem::read`, the data is returned owned, since other operations may run
+    /// while it is being replied with
+    fn read(
+        &self,
+        _ino: INode,
+        _offset: u64,
+        _size: u32,
+    ) -> impl Future<Output = FSResult<Vec<u8>, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn write(
+        &self,
+        _ino: INode,
+        _offset: u64,
+        _data: Vec<u8>,
+    ) -> impl Future<Output = FSResult<WriteResult, Self::Error>> + Send {
+        async { Err(FSError::BadFileDescriptor.into()) }
+    }
+
+    fn flush(
+        &self,
+        _ino: INode,
+        _fh: Filehandle,
+        _lock_owner: u64,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn fsync(
+        &self,
+        _ino: INode,
+        _fh: Filehandle,
+        _datasync: bool,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn make_dir(
+        &self,
+        _parent: INode,
+        _name: &OsStr,
+        _mode: u32,
+    ) -> impl Future<Output = FSResult<Lookup, Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn unlink(
+        &self,
+        _parent: INode,
+        _name: &OsStr,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn rmdir(
+        &self,
+        _parent: INode,
+        _name: &OsStr,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn rename(
+        &self,
+        _parent: INode,
+        _name: &OsStr,
+        _new_parent: INode,
+        _new_name: &OsStr,
+        _flags: RenameFlags,
+    ) -> impl Future<Output = FSResult<(), Self::Error>> + Send {
+        async { Err(FSError::NotImplemented.into()) }
+    }
+
+    fn statfs(
+        &self,
+        _ino: INode,
+    ) -> impl Future<Output = FSResult<StatfsReply, Self::Error>> + Send {
+        async { Ok(StatfsReply::default()) }
+    }
+}
+
+/// Serves a blocking `Filesystem` from an `AsyncRunner`, by running each call on tokio's blocking
+/// thread pool so the executor is never held up.
+///
+/// The filesystem sits behind a mutex, so its calls still run one at a time, as with `Runner`.
+/// Only the operations `AsyncFilesystem` covers are passed on.
+///
+/// A blocking call can't be stopped once it has started, and dropping its future only stops
+/// waiting for it, so a late call still takes effect. For that reason only read-only operations
+/// are ever timed out; anything which changes the filesystem, or bumps a lookup count, is always
+/// waited for.
+///
+/// ```no_run
+/// use simply_fuse::async_compat::BlockingAdapter;
+/// use simply_fuse::async_runner::AsyncRunner;
+/// use simply_fuse::error::FSError;
+/// use simply_fuse::Filesystem;
+///
+/// struct EmptyFS;
+///
+/// impl Filesystem for EmptyFS {
+///     type Error = FSError;
+/// }
+///
+/// # async fn run() -> simply_fuse::error::Result<()> {
+/// AsyncRunner::new(BlockingAdapter::new(EmptyFS), "/mnt/empty").run().await
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct BlockingAdapter<T> {
+    fs: Arc<Mutex<T>>,
+}
+
+impl<T: Filesystem + Send + 'static> BlockingAdapter<T> {
+    pub fn new(fs: T) -> BlockingAdapter<T> {
+        BlockingAdapter {
+            fs: Arc::new(Mutex::new(fs)),
+        }
+    }
+
+    /// Runs `f` against the filesystem on the blocking thread pool
+    async fn run<R, F>(&self, f: F) -> FSResult<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut T) -> FSResult<R, T::Error> + Send + 'static,
+    {
+        let fs = Arc::clone(&self.fs);
+
+        let result = task::spawn_blocking(move || {
+            // a panicking call doesn't leave the filesystem any worse off than a failed one
+            let mut fs = fs.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut fs).map_err(Into::into)
+        })
+        .await;
+
+        result.unwrap_or_else(|e| {
+            error!("filesystem call panicked: {}", e);
+            Err(FSError::custom(libc::EIO))
+        })
+    }
+}
+
+impl<T: Filesystem + Send + 'static> AsyncFilesystem for BlockingAdapter<T> {
+    type Error = FSError;
+
+    fn can_time_out(&self, kind: OpKind) -> bool {
+        matches!(
+            kind,
+            OpKind::Getattr | OpKind::Read | OpKind::Readdir | OpKind::Statfs
+        )
+    }
+
+    fn init(&self, capabilities: InitCapabilities) -> FSResult<InitCapabilities> {
+        let mut fs = self.fs.lock().unwrap_or_else(PoisonError::into_inner);
+        fs.init(capabilities).map_err(Into::into)
+    }
+
+    async fn destroy(&self) {
+        let _ = self
+            .run(|fs| {
+                fs.destroy();
+                Ok(())
+            })
+            .await;
+    }
+
+    async fn forget(&self, ino: INode, nlookup: u64) {
+        let _ = self
+            .run(move |fs| {
+                fs.forget(ino, nlookup);
+                Ok(())
+            })
+            .await;
+    }
+
+    fn lookup(&self, parent: INode, name: &OsStr) -> impl Future<Output = FSResult<Lookup>> + Send {
+        let name = name.to_os_string();
+        self.run(move |fs| fs.lookup(parent, &name))
+    }
+
+    fn getattr(&self, ino: INode) -> impl Future<Output = FSResult<FileAttributes>> + Send {
+        self.run(move |fs| fs.getattr(ino))
+    }
+
+    fn setattr(
+        &self,
+        ino: INode,
+        attr: SetFileAttributes,
+    ) -> impl Future<Output = FSResult<FileAttributes>> + Send {
+        self.run(move |fs| fs.setattr(ino, attr))
+    }
+
+    fn open(&self, ino: INode, flags: u32) -> impl Future<Output = FSResult<OpenFile>> + Send {
+        self.run(move |fs| fs.open(ino, flags))
+    }
+
+    fn release(
+        &self,
+        ino: INode,
+        fh: Filehandle,
+        flags: u32,
+        flush: bool,
+    ) -> impl Future<Output = FSResult<()>> + Send {
+        self.run(move |fs| fs.release(ino, fh, flags, flush))
+    }
+
+    fn open_dir(&self, ino: INode, flags: u32) -> impl Future<Output = FSResult<OpenDir>> + Send {
+        self.run(move |fs| fs.open_dir(ino, flags))
+    }
+
+    fn releasedir(
+        &self,
+        ino: INode,
+        fh: Filehandle,
+        flags: u32,
+    ) -> impl Future<Output = FSResult<()>> + Send {
+        self.run(move |fs| fs.releasedir(ino, fh, flags))
+    }
+
+    fn readdir(
+        &self,
+        dir: INode,
+        offset: u64,
+    ) -> impl Future<Output = FSResult<Vec<DirEntry>>> + Send {
+        self.run(move |fs| fs.readdir(dir, offset))
+    }
+
+    fn read(
+        &self,
+        ino: INode,
+        offset: u64,
+        size: u32,
+    ) -> impl Future<Output = FSResult<Vec<u8>>> + Send {
+        self.run(move |fs| {
+            fs.pre_read(ino, offset, size);
+            fs.read(ino, offset, size).map(<[u8]>::to_vec)
+        })
+    }
+
+    fn write(
+        &self,
+        ino: INode,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> impl Future<Output = FSResult<WriteResult>> + Send {
+        self.run(move |fs| {
+            // the kernel never sends more than max_write, which fits in a u32
+            let size = data.len() as u32;
+            let written = fs.write(ino, offset, size, data.as_slice())?;
+            fs.post_write(ino, offset, size, written.bytes_written());
+
+            Ok(written)
+        })
+    }
+
+    fn flush(
+        &self,
+        ino: INode,
+        fh: Filehandle,
+        lock_owner: u64,
+    ) -> impl Future<Output = FSResult<()>> + Send {
+        self.run(move |fs| fs.flush(ino, fh, lock_owner))
+    }
+
+    fn fsync(
+        &self,
+        ino: INode,
+        fh: Filehandle,
+        datasync: bool,
+    ) -> impl Future<Output = FSResult<()>> + Send {
+        self.run(move |fs| fs.fsync(ino, fh, datasync))
+    }
+
+    fn make_dir(
+        &self,
+        parent: INode,
+        name: &OsStr,
+        mode: u32,
+    ) -> impl Future<Output = FSResult<Lookup>> + Send {
+        let name = name.to_os_string();
+        self.run(move |fs| fs.make_dir(parent, &name, mode))
+    }
+
+    fn unlink(&self, parent: INode, name: &OsStr) -> impl Future<Output = FSResult<()>> + Send {
+        let name = name.to_os_string();
+        self.run(move |fs| fs.unlink(parent, &name))
+    }
+
+    fn rmdir(&self, parent: INode, name: &OsStr) -> impl Future<Output = FSResult<()>> + Send {
+        let name = name.to_os_string();
+        self.run(move |fs| fs.rmdir(parent, &name))
+    }
+
+    fn rename(
+        &self,
+        parent: INode,
+        name: &OsStr,
+        new_parent: INode,
+        new_name: &OsStr,
+        flags: RenameFlags,
+    ) -> impl Future<Output = FSResult<()>> + Send {
+        let (name, new_name) = (name.to_os_string(), new_name.to_os_string());
+        self.run(move |fs| fs.rename(parent, &name, new_parent, &new_name, flags))
+    }
+
+    fn statfs(&self, ino: INode) -> impl Future<Output = FSResult<StatfsReply>> + Send {
+        self.run(move |fs| fs.statfs(ino))
+    }
+}
+
+/// Mounts an `AsyncFilesystem` and serves its requests on the current tokio runtime
+pub struct AsyncRunner<T: AsyncFilesystem> {
+    mountpoint: PathBuf,
+    fs: Arc<T>,
+    kernel_config: KernelConfig,
+    config: RunnerConfig,
+    capabilities: InitCapabilities,
+    timeout_policy: Option<TimeoutPolicy>,
+}
+
+impl<T: AsyncFilesystem> AsyncRunner<T> {
+    pub fn new<P: AsRef<Path>>(fs: T, mountpoint: P) -> AsyncRunner<T> {
+        AsyncRunner {
+            mountpoint: mountpoint.as_ref().to_path_buf(),
+            fs: Arc::new(fs),
+            kernel_config: KernelConfig::default(),
+            config: RunnerConfig::default(),
+            capabilities: InitCapabilities::default(),
+            timeout_policy: None,
+        }
+    }
+
+    /// Uses `config` when mounting. Capability flags are still replaced by what
+    /// `AsyncFilesystem::init` agrees to, as with `Runner`.
+    pub fn with_kernel_config(mut self, config: KernelConfig) -> AsyncRunner<T> {
+        self.kernel_config = config;
+        self
+    }
+
+    /// Mounts with the options in `config`, applied on top of the kernel config. Its
+    /// `operation_timeout` is used unless a timeout policy is set.
+    pub fn with_config(mut self, config: RunnerConfig) -> AsyncRunner<T> {
+        self.config = config;
+        self
+    }
+
+    /// Gives up on any operation still running after the deadline `policy` sets for its kind.
+    /// Unlike with `Runner`, the operation is dropped rather than run to completion, and the
+    /// kernel receives `ETIMEDOUT` straight away. See `TimeoutPolicy` for why it isn't `EINTR`.
+    ///
+    /// Operations `AsyncFilesystem::can_time_out` rules out are always waited for.
+    pub fn with_timeout_policy(mut self, policy: TimeoutPolicy) -> AsyncRunner<T> {
+        self.timeout_policy = Some(policy);
+        self
+    }
+
+    /// Shorthand for `with_timeout_policy` with `TimeoutPolicy::new(timeout)`
+    pub fn with_operation_timeout(self, timeout: Duration) -> AsyncRunner<T> {
+        self.with_timeout_policy(TimeoutPolicy::new(timeout))
+    }
+
+    /// Mounts the filesystem and serves requests until it is unmounted. Waiting for requests
+    /// and mounting both block, so they run on tokio's blocking thread pool.
+    pub async fn run(self) -> Result<()> {
+        let AsyncRunner {
+            mountpoint,
+            fs,
+            mut kernel_config,
+            config,
+            capabilities,
+            timeout_policy,
+        } = self;
+
+        if !mountpoint.is_dir() {
+            let msg = format!("{} is not a directory", mountpoint.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+
+        config.apply(&mut kernel_config);
+        let timeout_policy =
+            timeout_policy.or_else(|| config.operation_timeout.map(TimeoutPolicy::new));
+
+        let capabilities = fs
+            .init(capabilities)
+            .map_err(|e| Error::InitError(e.into()))?;
+        apply_capabilities(&mut kernel_config, capabilities);
+
+        let session = task::spawn_blocking(move || Session::mount(mountpoint, kernel_config))
+            .await
+            .expect("mounting panicked")?;
+        let session = Arc::new(session);
+        let notifier = session.notifier();
+
+        let mut tasks = JoinSet::new();
+        let result = loop {
+            let next = {
+                let session = Arc::clone(&session);
+                task::spawn_blocking(move || session.next_request())
+                    .await
+                    .expect("reading a request panicked")
+            };
+
+            let req = match next {
+                Ok(Some(req)) => req,
+                Ok(None) => break Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("failed to read the next request: {}", e);
+                    break Err(e.into());
+                }
+            };
+
+            let fs = Arc::clone(&fs);
+            let notifier = notifier.clone();
+            let timeout = timeout_policy.as_ref().and_then(|policy| {
+                // requests which fail to decode are answered by `dispatch`
+                let kind = req
+                    .operation()
+                    .map_or(OpKind::Unknown, |op| OpKind::from(&op));
+                deadline_for(&*fs, policy, kind).map(|timeout| (kind, timeout))
+            });
+
+            tasks.spawn(async move {
+                let result = match timeout {
+                    None => dispatch(&*fs, &req, &notifier).await,
+                    Some((kind, timeout)) => {
+                        time::timeout(timeout, dispatch(&*fs, &req, &notifier))
+                            .await
+                            .unwrap_or_else(|_| reply_timed_out(&req, kind, timeout))
+                    }
+                };
+
+                if let Err(e) = result {
+                    error!("failed to reply to request {}: {}", req.unique(), e);
+                }
+            });
+
+            // Reap finished tasks so the set doesn't grow without bound
+            while tasks.try_join_next().is_some() {}
+        };
+
+        while tasks.join_next().await.is_some() {}
+        fs.destroy().await;
+
+        result
+    }
+}
+
+/// Returns how long an operation of `kind` may run before it's dropped, if it may be dropped at
+/// all
+fn deadline_for<T: AsyncFilesystem>(
+    fs: &T,
+    policy: &TimeoutPolicy,
+    kind: OpKind,
+) -> Option<Duration> {
+    fs.can_time_out(kind).then(|| policy.timeout_for(kind))
+}
+
+/// Replies to a request whose operation was dropped for running past `timeout`
+fn reply_timed_out(req: &Request, kind: OpKind, timeout: Duration) -> Result<()> {
+    warn!("{:?} exceeded its timeout of {:?}", kind, timeout);
+
+    // forgets are never replied to
+    if kind != OpKind::Forget {
+        req.reply_error(FSError::TimedOut.to_libc_error())
+            .map_err(PolyfuseError::ReplyErrError)?;
+    }
+
+    Ok(())
+}
+
+/// Replies to `req` with either `result` or its error
+fn reply_with<R: polyfuse::bytes::Bytes, E: Into<FSError>>(
+    req: &Request,
+    kind: OpKind,
+    result: FSResult<R, E>,
+) -> Result<()> {
+    match result {
+        Ok(out) => req.reply(out).map_err(PolyfuseError::ReplyError)?,
+        Err(e) => {
+            let e = e.into();
+            warn!("{:?} error occured: {:#?}", kind, e);
+            req.reply_error(e.to_libc_error())
+                .map_err(PolyfuseError::ReplyErrError)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn attr_out(ino: INode, attrs: FileAttributes) -> reply::AttrOut {
+    let mut out = reply::AttrOut::default();
+
+    out.ttl(attrs.ttl());
+    attrs.copy_attrs_to(ino, out.attr());
+
+    out
+}
+
+async fn dispatch<T: AsyncFilesystem>(fs: &T, req: &Request, notifier: &Notifier) -> Result<()> {
+    let op = req.operation().map_err(PolyfuseError::DecodeError)?;
+    let kind = OpKind::from(&op);
+
+    match op {
+        Operation::Forget(forgets) => {
+            for forget in forgets.iter() {
+                fs.forget(forget.ino().into(), forget.nlookup()).await;
+            }
+
+            Ok(())
+        }
+        Operation::Lookup(op) => {
+            let result = fs.lookup(op.parent().into(), op.name()).await;
+            reply_with(req, kind, result.map(reply::EntryOut::from))
+        }
+        Operation::Getattr(op) => {
+            let result = fs.getattr(op.ino().into()).await;
+            reply_with(req, kind, result.map(|x| attr_out(op.ino().into(), x)))
+        }
+        Operation::Setattr(op) => {
+            let result = fs.setattr(op.ino().into(), set_file_attributes(&op)).await;
+            reply_with(req, kind, result.map(|x| attr_out(op.ino().into(), x)))
+        }
+        Operation::Open(op) => {
+            let result = fs.open(op.ino().into(), op.flags()).await;
+            reply_with(req, kind, result.map(reply::OpenOut::from))
+        }
+        Operation::Release(op) => {
+            let fh = Filehandle::from_raw(op.fh());
+            let result = fs
+                .release(op.ino().into(), fh, op.flags(), op.flush())
+                .await;
+            reply_with(req, kind, result)
+        }
+        Operation::Opendir(op) => {
+            let result = fs.open_dir(op.ino().into(), op.flags()).await;
+            reply_with(req, kind, result.map(reply::OpenOut::from))
+        }
+        Operation::Releasedir(op) => {
+            let fh = Filehandle::from_raw(op.fh());
+            let result = fs.releasedir(op.ino().into(), fh, op.flags()).await;
+            reply_with(req, kind, result)
+        }
+        Operation::Readdir(op) if op.mode() == op::ReaddirMode::Normal => {
+            let result = fs.readdir(op.ino().into(), op.offset()).await;
+
+            reply_with(
+                req,
+                kind,
+                result.map(|entries| {
+                    let mut out = reply::ReaddirOut::new(op.size() as usize);
+
+                    for x in &entries {
+                        // returns true once the buffer is full
+                        if out.entry(
+                            &x.name,
+                            x.inode.to_u64(),
+                            x.typ.to_libc_type() as u32,
+                            x.offset,
+                        ) {
+                            break;
+                        }
+                    }
+
+                    out
+                }),
+            )
+        }
+        Operation::Read(op) => {
+            let result = fs.read(op.ino().into(), op.offset(), op.size()).await;
+            reply_with(req, kind, result)
+        }
+        Operation::Write(op, mut data) => {
+            let mut buf = Vec::with_capacity(op.size() as usize);
+            if let Err(e) = data.read_to_end(&mut buf) {
+                warn!("failed to read write data: {}", e);
+                return reply_with::<(), _>(req, kind, Err(FSError::InvalidArgument));
+            }
+
+            match fs.write(op.ino().into(), op.offset(), buf).await {
+                Ok(written) => {
+                    let mut out = reply::WriteOut::default();
+                    out.size(written.bytes_written());
+                    reply_with::<_, FSError>(req, kind, Ok(out))?;
+
+                    if written.invalidate_cache() {
+                        if let Err(e) = notifier.inval_inode(op.ino(), 0, 0) {
+                            warn!("failed to invalidate cache for {}: {}", op.ino(), e);
+                        }
+                    }
+
+                    Ok(())
+                }
+                Err(e) => reply_with::<(), _>(req, kind, Err(e)),
+            }
+        }
+        Operation::Flush(op) => {
+            let fh = Filehandle::from_raw(op.fh());
+            let result = fs
+                .flush(op.ino().into(), fh, op.lock_owner().into_raw())
+                .await;
+            reply_with(req, kind, result)
+        }
+        Operation::Fsync(op) => {
+            let fh = Filehandle::from_raw(op.fh());
+            let result = fs.fsync(op.ino().into(), fh, op.datasync()).await;
+            reply_with(req, kind, result)
+        }
+        Operation::Mkdir(op) => {
+            let result = fs.make_dir(op.parent().into(), op.name(), op.mode()).await;
+            reply_with(req, kind, result.map(reply::EntryOut::from))
+        }
+        Operation::Unlink(op) => {
+            let result = fs.unlink(op.parent().into(), op.name()).await;
+            reply_with(req, kind, result)
+        }
+        Operation::Rmdir(op) => {
+            let result = fs.rmdir(op.parent().into(), op.name()).await;
+            reply_with(req, kind, result)
+        }
+        Operation::Rename(op) => {
+            let flags = match RenameFlags::from_libc_type(op.flags()) {
+                Some(flags) => flags,
+                None => {
+                    return reply_with::<(), _>(req, kind, Err(FSError::InvalidFlags(op.flags())))
+                }
+            };
+
+            let result = fs
+                .rename(
+                    op.parent().into(),
+                    op.name(),
+                    op.newparent().into(),
+                    op.newname(),
+                    flags,
+                )
+                .await;
+            reply_with(req, kind, result)
+        }
+        Operation::Statfs(op) => {
+            let result = fs.statfs(op.ino().into()).await;
+            reply_with(req, kind, result.map(reply::StatfsOut::from))
+        }
+        op => {
+            error!("unimplemented: {:?}", op);
+            reply_with::<(), _>(req, kind, Err(FSError::NotImplemented))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::BufRead;
+    use std::thread;
+
+    use tokio::runtime;
+
+    /// Takes a while over every write, like a filesystem stuck on the network
+    #[derive(Default)]
+    struct SlowFS {
+        data: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Filesystem for SlowFS {
+        type Error = FSError;
+
+        fn write<T: BufRead>(
+            &mut self,
+            _ino: INode,
+            _offset: u64,
+            size: u32,
+            mut buf: T,
+        ) -> FSResult<WriteResult> {
+            thread::sleep(Duration::from_millis(50));
+
+            let mut data = self.data.lock().unwrap();
+            data.clear();
+            buf.read_to_end(&mut data)?;
+
+            Ok(size.into())
+        }
+    }
+
+    #[test]
+    fn slow_blocking_writes_are_never_timed_out() {
+        let rt = runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        let fs = SlowFS::default();
+        let data = Arc::clone(&fs.data);
+        let fs = BlockingAdapter::new(fs);
+        let policy = TimeoutPolicy::new(Duration::from_millis(10));
+
+        assert_eq!(deadline_for(&fs, &policy, OpKind::Write), None);
+        assert_eq!(
+            deadline_for(&fs, &policy, OpKind::Getattr),
+            Some(Duration::from_millis(10))
+        );
+
+        let written = rt.block_on(fs.write(INode::from(2), 0, b"data".to_vec()));
+        assert_eq!(written.unwrap().bytes_written(), 4);
+        assert_eq!(*data.lock().unwrap(), b"data");
+
+        // Dropping the future doesn't stop the write, which is why it must not be timed out
+        let late = rt.block_on(async {
+            let write = fs.write(INode::from(2), 0, b"late".to_vec());
+            time::timeout(Duration::from_millis(10), write).await
+        });
+        assert!(late.is_err());
+
+        rt.block_on(async { time::sleep(Duration::from_millis(100)).await });
+        assert_eq!(*data.lock().unwrap(), b"late");
+    }
+}
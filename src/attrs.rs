@@ -1,8 +1,11 @@
-use std::time::Duration;
+use crate::error::{FSError, FSResult};
+use crate::{AccessMask, FileType};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use typed_builder::TypedBuilder;
 
-#[derive(Copy, Clone, Debug, TypedBuilder)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, TypedBuilder)]
 #[builder(field_defaults(default, setter(into)))]
 pub struct FileAttributes {
     #[builder(!default, setter(!strip_option))]
@@ -27,6 +30,15 @@ pub struct FileAttributes {
 }
 
 impl FileAttributes {
+    /// Creates attributes for a file of type `file_type` with the permission bits `perms` and
+    /// the given size. Everything else is left at its default.
+    pub fn from_size_and_type(size: u64, file_type: FileType, perms: u32) -> FileAttributes {
+        FileAttributes::builder()
+            .mode(file_type.to_mode_bits() | (perms & 0o7777))
+            .size(size)
+            .build()
+    }
+
     pub fn mode(&self) -> u32 {
         self.mode
     }
@@ -80,48 +92,109 @@ impl FileAttributes {
         self.ttl
     }
 
-    pub fn set_mode(&mut self, mode: u32) {
+    pub fn set_mode(&mut self, mode: u32) -> &mut Self {
         self.mode = mode;
+        self
     }
 
-    pub fn set_size(&mut self, size: u64) {
+    pub fn set_size(&mut self, size: u64) -> &mut Self {
         self.size = size;
+        self
     }
 
-    pub fn set_nlink(&mut self, nlink: u32) {
+    pub fn set_nlink(&mut self, nlink: u32) -> &mut Self {
         self.nlink = nlink;
+        self
     }
 
-    pub fn set_uid(&mut self, uid: u32) {
+    pub fn set_uid(&mut self, uid: u32) -> &mut Self {
         self.uid = uid;
+        self
     }
 
-    pub fn set_gid(&mut self, gid: u32) {
+    pub fn set_gid(&mut self, gid: u32) -> &mut Self {
         self.gid = gid;
+        self
     }
 
-    pub fn set_rdev(&mut self, rdev: u32) {
+    pub fn set_rdev(&mut self, rdev: u32) -> &mut Self {
         self.rdev = rdev;
+        self
     }
 
-    pub fn set_blksize(&mut self, blksize: u32) {
+    pub fn set_blksize(&mut self, blksize: u32) -> &mut Self {
         self.blksize = blksize;
+        self
     }
-    pub fn set_blocks(&mut self, blocks: u64) {
+
+    pub fn set_blocks(&mut self, blocks: u64) -> &mut Self {
         self.blocks = blocks;
+        self
     }
 
-    pub fn set_atime(&mut self, atime: Duration) {
+    pub fn set_atime(&mut self, atime: Duration) -> &mut Self {
         self.atime = atime;
+        self
     }
-    pub fn set_mtime(&mut self, mtime: Duration) {
+
+    pub fn set_mtime(&mut self, mtime: Duration) -> &mut Self {
         self.mtime = mtime;
+        self
     }
-    pub fn set_ctime(&mut self, ctime: Duration) {
+
+    pub fn set_ctime(&mut self, ctime: Duration) -> &mut Self {
         self.ctime = ctime;
+        self
     }
-    pub fn set_ttl(&mut self, ttl: Duration) {
+
+    pub fn set_ttl(&mut self, ttl: Duration) -> &mut Self {
         self.ttl = ttl;
+        self
+    }
+
+    /// Checks `mask` against the permission bits that apply to the given user and group. Root may
+    /// read and write anything and search any directory, but can only execute files with at
+    /// least one execute bit set.
+    ///
+    /// Only the primary group is considered, since FUSE doesn't tell us about supplementary ones.
+    pub fn permits(&self, uid: u32, gid: u32, mask: AccessMask) -> bool {
+        if uid == 0 {
+            let is_dir = self.mode & libc::S_IFMT == libc::S_IFDIR;
+            return is_dir || !mask.contains(AccessMask::EXECUTE) || self.mode & 0o111 != 0;
+        }
+
+        let mask = mask.to_libc_type();
+
+        let bits = if uid == self.uid {
+            self.mode >> 6
+        } else if gid == self.gid {
+            self.mode >> 3
+        } else {
+            self.mode
+        };
+
+        bits & mask == mask
+    }
+
+    /// Like `permits`, but fails with `FSError::AccessDenied` instead of returning false, for
+    /// use with `?` in `open`, `read` or `write`.
+    pub fn check_access(&self, uid: u32, gid: u32, mask: AccessMask) -> FSResult<()> {
+        if self.permits(uid, gid, mask) {
+            Ok(())
+        } else {
+            Err(FSError::AccessDenied)
+        }
+    }
+
+    /// Sets the access, modification and change times to the current time. Falls back to the
+    /// epoch if the system clock is set before it.
+    pub fn with_now_timestamps(mut self) -> FileAttributes {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO);
+
+        self.set_atime(now).set_mtime(now).set_ctime(now);
+        self
     }
 
     #[deny(unused_variables)]
@@ -160,16 +233,25 @@ impl FileAttributes {
     }
 }
 
-#[derive(Copy, Clone, Debug, TypedBuilder)]
+/// Attributes to change on a file. Every field defaults to `None`, meaning that attribute is left
+/// as it is, so only the attributes being changed need to be given to the builder.
+#[derive(Copy, Clone, Debug, Default, TypedBuilder)]
 pub struct SetFileAttributes {
+    #[builder(default)]
     mode: Option<u32>,
+    #[builder(default)]
     size: Option<u64>,
 
+    #[builder(default)]
     uid: Option<u32>,
+    #[builder(default)]
     gid: Option<u32>,
 
+    #[builder(default)]
     atime: Option<Duration>,
+    #[builder(default)]
     mtime: Option<Duration>,
+    #[builder(default)]
     ctime: Option<Duration>,
 }
 
@@ -201,4 +283,77 @@ impl SetFileAttributes {
     pub fn ctime(&self) -> Option<Duration> {
         self.ctime
     }
+
+    /// Returns the new size if `size` is the only attribute being set, which makes this a plain
+    /// truncate.
+    pub fn truncate_size(&self) -> Option<u64> {
+        let rest = SetFileAttributes {
+            size: None,
+            ..*self
+        };
+
+        if rest.is_noop() {
+            self.size
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if no attribute would be changed by applying these attributes
+    #[deny(unused_variables)]
+    pub fn is_noop(&self) -> bool {
+        // Same trick as `FileAttributes::apply_attrs`, so new fields can't be forgotten here
+        let SetFileAttributes {
+            mode,
+            size,
+            uid,
+            gid,
+            atime,
+            mtime,
+            ctime,
+        } = self;
+
+        mode.is_none()
+            && size.is_none()
+            && uid.is_none()
+            && gid.is_none()
+            && atime.is_none()
+            && mtime.is_none()
+            && ctime.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_checks_access_mask() {
+        let attrs = FileAttributes::builder()
+            .mode(libc::S_IFREG | 0o640)
+            .uid(1000u32)
+            .gid(100u32)
+            .build();
+
+        assert!(attrs.permits(1000, 100, AccessMask::READ | AccessMask::WRITE));
+        assert!(attrs.permits(1001, 100, AccessMask::READ));
+        assert!(!attrs.permits(1001, 100, AccessMask::WRITE));
+        assert!(attrs.permits(1002, 1002, AccessMask::EXISTS));
+        assert!(!attrs.permits(0, 0, AccessMask::EXECUTE));
+
+        let dir = FileAttributes::builder()
+            .mode(libc::S_IFDIR | 0o600)
+            .uid(1000u32)
+            .build();
+        assert!(dir.permits(0, 0, AccessMask::EXECUTE));
+        assert!(!dir.permits(1000, 0, AccessMask::EXECUTE));
+        assert!(matches!(
+            attrs.check_access(1001, 100, AccessMask::WRITE),
+            Err(FSError::AccessDenied)
+        ));
+        assert_eq!(
+            AccessMask::from_libc_type(0o7),
+            AccessMask::READ | AccessMask::WRITE | AccessMask::EXECUTE
+        );
+    }
 }
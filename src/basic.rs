@@ -1,8 +1,14 @@
-use crate::{FileAttributes, FileType, INode, SetFileAttributes};
-
-use std::collections::HashMap;
+use crate::error::{FSError, FSResult};
+use crate::journal::{Journal, NullJournal};
+use crate::{
+    DirEntry, FileAttributes, FileType, Filehandle, INode, SetFileAttributes, SetXAttrFlags,
+    XAttrRef,
+};
+
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::ffi::{OsStr, OsString};
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
 
 pub type DirChildren = HashMap<OsString, INode>;
 pub const ROOT_INODE: INode = INode(1);
@@ -11,9 +17,56 @@ pub trait Attributable {
     fn getattrs(&self) -> FileAttributes;
 }
 
+/// An `Attributable` object whose attributes can also be changed
+pub trait AttributableMut: Attributable {
+    fn setattrs(&mut self, attrs: FileAttributes);
+
+    /// Applies `attrs` on top of the current attributes
+    fn apply_attrs(&mut self, attrs: SetFileAttributes) {
+        let mut cur = self.getattrs();
+        cur.apply_attrs(attrs);
+        self.setattrs(cur);
+    }
+}
+
 /// Represents an object that acts like a file on the filesystem
 pub trait Filelike: Attributable {}
 
+/// A symbolic link, pointing at `target`
+#[derive(Debug)]
+pub struct Symlink {
+    target: OsString,
+    attrs: FileAttributes,
+}
+
+impl Symlink {
+    pub fn new(target: OsString) -> Symlink {
+        Symlink {
+            attrs: FileAttributes::builder()
+                .mode(libc::S_IFLNK | 0o777)
+                .size(target.len() as u64)
+                .build(),
+            target,
+        }
+    }
+
+    pub fn target(&self) -> &OsStr {
+        &self.target
+    }
+}
+
+impl Attributable for Symlink {
+    fn getattrs(&self) -> FileAttributes {
+        self.attrs
+    }
+}
+
+impl AttributableMut for Symlink {
+    fn setattrs(&mut self, attrs: FileAttributes) {
+        self.attrs = attrs;
+    }
+}
+
 #[derive(Debug)]
 pub struct Directory {
     children: DirChildren,
@@ -44,6 +97,12 @@ impl Attributable for Directory {
     }
 }
 
+impl AttributableMut for Directory {
+    fn setattrs(&mut self, attrs: FileAttributes) {
+        self.attrs = attrs;
+    }
+}
+
 impl Directory {
     pub fn get(&self, name: &OsStr) -> Option<&INode> {
         self.children.get(name)
@@ -54,6 +113,15 @@ impl Directory {
             iter: self.children.iter(),
         }
     }
+
+    /// Returns the number of direct children, not counting "." and ".."
+    pub fn child_count(&self) -> usize {
+        self.children.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.children.is_empty()
+    }
 }
 
 pub struct DirIter<'a> {
@@ -72,6 +140,8 @@ impl<'a> Iterator for DirIter<'a> {
 #[derive(Debug)]
 pub struct INodeEntry<F> {
     parent: Option<INode>,
+    /// The directories holding this entry's other names, if it's hard linked
+    links: Vec<INode>,
     kind: INodeKind<F>,
 }
 
@@ -88,6 +158,7 @@ impl<F> INodeEntry<F> {
         match self.kind() {
             INodeKind::Directory(_) => FileType::Directory,
             INodeKind::File(_) => FileType::Regular,
+            INodeKind::Symlink(_) => FileType::Link,
         }
     }
 
@@ -95,6 +166,12 @@ impl<F> INodeEntry<F> {
         self.parent
     }
 
+    /// How many names the table holds for this entry, which is more than 1 once it has been
+    /// hard linked with `INodeTable::link_entry`. Always 1 for directories, which can't be linked.
+    pub fn link_count(&self) -> u32 {
+        1 + self.links.len() as u32
+    }
+
     pub fn as_dir(&self) -> Option<&Directory> {
         match self.kind() {
             INodeKind::Directory(ref dir) => Some(dir),
@@ -109,6 +186,13 @@ impl<F> INodeEntry<F> {
         }
     }
 
+    pub fn as_symlink(&self) -> Option<&Symlink> {
+        match self.kind() {
+            INodeKind::Symlink(link) => Some(link),
+            _ => None,
+        }
+    }
+
     pub fn as_file(&self) -> Option<&F> {
         match &self.kind() {
             INodeKind::File(file) => Some(file),
@@ -129,13 +213,49 @@ impl<F> INodeEntry<F> {
             _ => None,
         }
     }
+
+    /// Returns the name this entry has in its parent directory, or `None` for the root. Since
+    /// entries don't know their own inode, this scans the parent's children for one which points
+    /// back at this entry.
+    pub fn name_in_parent<'a>(&self, table: &'a INodeTable<F>) -> Option<&'a OsStr> {
+        table
+            .get(self.parent?)?
+            .as_dir()?
+            .children()
+            .find(|(_, child)| table.get(*child).is_some_and(|x| std::ptr::eq(x, self)))
+            .map(|(name, _)| name.as_os_str())
+    }
 }
 
 impl<T: Attributable> INodeEntry<T> {
+    /// Returns the entry's attributes. For files and symlinks, `nlink` is always the entry's
+    /// `link_count`, since the table is what knows how many names refer to it.
     pub fn getattrs(&self) -> FileAttributes {
-        match self.kind() {
-            INodeKind::Directory(dir) => dir.getattrs(),
+        let mut attrs = match self.kind() {
+            INodeKind::Directory(dir) => return dir.getattrs(),
             INodeKind::File(file) => file.getattrs(),
+            INodeKind::Symlink(link) => link.getattrs(),
+        };
+
+        attrs.set_nlink(self.link_count());
+        attrs
+    }
+}
+
+impl<T: AttributableMut> INodeEntry<T> {
+    pub fn setattrs(&mut self, attrs: FileAttributes) {
+        match self.kind_mut() {
+            INodeKind::Directory(dir) => dir.setattrs(attrs),
+            INodeKind::File(file) => file.setattrs(attrs),
+            INodeKind::Symlink(link) => link.setattrs(attrs),
+        }
+    }
+
+    pub fn apply_attrs(&mut self, attrs: SetFileAttributes) {
+        match self.kind_mut() {
+            INodeKind::Directory(dir) => dir.apply_attrs(attrs),
+            INodeKind::File(file) => file.apply_attrs(attrs),
+            INodeKind::Symlink(link) => link.apply_attrs(attrs),
         }
     }
 }
@@ -148,6 +268,7 @@ impl<F: Filelike> IntoINodeEntry<F> for F {
     fn with_parent(self, parent: INode) -> INodeEntry<F> {
         INodeEntry {
             parent: Some(parent),
+            links: Vec::new(),
             kind: INodeKind::File(self),
         }
     }
@@ -157,14 +278,36 @@ impl<F> IntoINodeEntry<F> for Directory {
     fn with_parent(self, parent: INode) -> INodeEntry<F> {
         INodeEntry {
             parent: Some(parent),
+            links: Vec::new(),
             kind: INodeKind::Directory(self),
         }
     }
 }
 
+impl<F> IntoINodeEntry<F> for Symlink {
+    fn with_parent(self, parent: INode) -> INodeEntry<F> {
+        INodeEntry {
+            parent: Some(parent),
+            links: Vec::new(),
+            kind: INodeKind::Symlink(self),
+        }
+    }
+}
+
+impl<F> IntoINodeEntry<F> for INodeKind<F> {
+    fn with_parent(self, parent: INode) -> INodeEntry<F> {
+        INodeEntry {
+            parent: Some(parent),
+            links: Vec::new(),
+            kind: self,
+        }
+    }
+}
+
 impl<F> IntoINodeEntry<F> for INodeEntry<F> {
     fn with_parent(mut self, parent: INode) -> INodeEntry<F> {
         self.parent = Some(parent);
+        self.links.clear();
         self
     }
 }
@@ -173,6 +316,154 @@ impl<F> IntoINodeEntry<F> for INodeEntry<F> {
 pub enum INodeKind<F> {
     Directory(Directory),
     File(F),
+    Symlink(Symlink),
+}
+
+impl<F> INodeKind<F> {
+    /// Returns a human-readable name for this kind, for use in logs and error messages
+    pub const fn name(&self) -> &'static str {
+        match self {
+            INodeKind::Directory(_) => "directory",
+            INodeKind::File(_) => "file",
+            INodeKind::Symlink(_) => "symlink",
+        }
+    }
+}
+
+/// Checks that a listing returned by `Filesystem::readdir` for offset 0 starts with the `.` and
+/// `..` entries at offsets 1 and 2, as the kernel expects.
+///
+/// ```
+/// # use simply_fuse::basic::{check_readdir_entries, ROOT_INODE};
+/// # use simply_fuse::{DirEntry, FileType};
+/// let dot = |name: &str, offset| {
+///     DirEntry::builder()
+///         .name(name.into())
+///         .inode(ROOT_INODE)
+///         .typ(FileType::Directory)
+///         .offset(offset)
+///         .build()
+/// };
+///
+/// assert!(check_readdir_entries(&[dot(".", 1), dot("..", 2)]).is_ok());
+/// assert!(check_readdir_entries(&[dot("..", 1), dot(".", 2)]).is_err());
+/// ```
+pub fn check_readdir_entries(entries: &[DirEntry]) -> Result<(), &'static str> {
+    match entries {
+        [dot, dotdot, ..] => {
+            if dot.name != "." || dot.offset != 1 {
+                Err("first entry must be \".\" at offset 1")
+            } else if dotdot.name != ".." || dotdot.offset != 2 {
+                Err("second entry must be \"..\" at offset 2")
+            } else {
+                Ok(())
+            }
+        }
+        _ => Err("readdir must return at least the \".\" and \"..\" entries"),
+    }
+}
+
+/// Maps open file handles to whatever state a filesystem keeps for them
+#[derive(Debug)]
+pub struct OpenHandleTable<S> {
+    handles: HashMap<Filehandle, S>,
+}
+
+impl<S> OpenHandleTable<S> {
+    /// Stores `state` under a newly generated handle, which can be returned from `open`
+    pub fn open(&mut self, state: S) -> Filehandle {
+        let fh = Filehandle::generate();
+        self.handles.insert(fh, state);
+
+        fh
+    }
+
+    pub fn get(&self, fh: Filehandle) -> Option<&S> {
+        self.handles.get(&fh)
+    }
+
+    pub fn get_mut(&mut self, fh: Filehandle) -> Option<&mut S> {
+        self.handles.get_mut(&fh)
+    }
+
+    /// Forgets `fh`, returning its state
+    pub fn close(&mut self, fh: Filehandle) -> Option<S> {
+        self.handles.remove(&fh)
+    }
+}
+
+impl<S> Default for OpenHandleTable<S> {
+    fn default() -> Self {
+        OpenHandleTable {
+            handles: HashMap::new(),
+        }
+    }
+}
+
+/// In-memory storage for a single inode's extended attributes. The methods follow the contracts
+/// of the matching `Filesystem` xattr methods, so they can be returned from them directly.
+#[derive(Debug, Default, Clone)]
+pub struct XAttrStore(HashMap<OsString, Vec<u8>>);
+
+impl XAttrStore {
+    pub fn get(&self, name: &OsStr) -> Option<&[u8]> {
+        self.0.get(name).map(|x| x.as_slice())
+    }
+
+    /// Returns the attribute in the form `Filesystem::getxattr` expects. When `max_len` is 0
+    /// only the length is filled in, and values longer than `max_len` are rejected with
+    /// `FSError::BufferWouldOverflow`.
+    pub fn get_ref(&self, name: &OsStr, max_len: u32) -> FSResult<XAttrRef<'_>> {
+        let value = self.get(name).ok_or(FSError::NoAttribute)?;
+
+        if max_len == 0 {
+            Ok(XAttrRef::new(&[], value.len()))
+        } else if value.len() > max_len as usize {
+            Err(FSError::BufferWouldOverflow)
+        } else {
+            Ok(XAttrRef::new(value, value.len()))
+        }
+    }
+
+    /// Sets `name` to `value`. `SetXAttrFlags::Create` fails if the attribute already exists,
+    /// while `SetXAttrFlags::Replace` fails if it doesn't. `SetXAttrFlags::CreateOrReplace`
+    /// always succeeds.
+    pub fn set(&mut self, name: &OsStr, value: &[u8], flags: SetXAttrFlags) -> FSResult<()> {
+        match (flags, self.0.contains_key(name)) {
+            (SetXAttrFlags::Create, true) => return Err(FSError::AlreadyExists),
+            (SetXAttrFlags::Replace, false) => return Err(FSError::NoAttribute),
+            _ => (),
+        }
+
+        self.0.insert(name.to_os_string(), value.to_vec());
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &OsStr) -> FSResult<()> {
+        self.0.remove(name).map(|_| ()).ok_or(FSError::NoAttribute)
+    }
+
+    /// Lists the attribute names in the form `Filesystem::listxattrs` expects
+    pub fn list(&self, max_len: u32) -> FSResult<(OsString, u32)> {
+        // every name is followed by a nul byte
+        let full_len: usize = self.0.keys().map(|name| name.len() + 1).sum();
+
+        if max_len == 0 {
+            return Ok((OsString::new(), full_len as u32));
+        }
+
+        if full_len > max_len as usize {
+            return Err(FSError::BufferWouldOverflow);
+        }
+
+        let mut names = Vec::with_capacity(full_len);
+        for name in self.0.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(b'\0');
+        }
+
+        Ok((OsStr::from_bytes(&names).to_os_string(), full_len as u32))
+    }
 }
 
 /// A generic INodeTable which allows indexing by paths and inodes
@@ -182,9 +473,19 @@ pub enum INodeKind<F> {
 pub struct INodeTable<F> {
     map: HashMap<INode, INodeEntry<F>>,
     cur_ino: INode,
+    journal: Box<dyn Journal<F> + Send>,
 }
 
 impl<F> INodeTable<F> {
+    /// Creates a table containing only the root, which logs every change to `journal` before
+    /// applying it
+    pub fn with_journal(journal: Box<dyn Journal<F> + Send>) -> INodeTable<F> {
+        INodeTable {
+            journal,
+            ..INodeTable::default()
+        }
+    }
+
     pub fn push_entry<E: IntoINodeEntry<F>>(
         &mut self,
         parent: INode,
@@ -193,13 +494,34 @@ impl<F> INodeTable<F> {
     ) -> Option<INode> {
         let ino = self.next_open_inode();
         let parent_dir = self.map.get_mut(&parent)?.as_dir_mut()?;
+        let entry = entry.with_parent(parent);
+
+        self.journal.log_insert(ino, &name, &entry);
 
         parent_dir.children.insert(name, ino);
-        self.map.insert(ino, entry.with_parent(parent));
+        self.map.insert(ino, entry);
 
         Some(ino)
     }
 
+    /// Shorthand for `push_entry` when inserting a file
+    pub fn push_file(&mut self, parent: INode, name: OsString, file: F) -> Option<INode>
+    where
+        F: Filelike,
+    {
+        self.push_entry(parent, name, file)
+    }
+
+    /// Creates a symlink named `name` in `parent` which points at `target`
+    pub fn push_symlink(
+        &mut self,
+        parent: INode,
+        name: OsString,
+        target: OsString,
+    ) -> Option<INode> {
+        self.push_entry(parent, name, Symlink::new(target))
+    }
+
     pub fn get<T: Into<INode>>(&self, ino: T) -> Option<&INodeEntry<F>> {
         self.map.get(&ino.into())
     }
@@ -208,7 +530,218 @@ impl<F> INodeTable<F> {
         self.map.get_mut(&ino.into())
     }
 
+    /// Returns the root entry. The root is inserted when the table is created and can never be
+    /// removed, so unlike `get` this doesn't need to return an `Option`.
+    pub fn root(&self) -> &INodeEntry<F> {
+        self.get(ROOT_INODE)
+            .expect("the root inode is always present")
+    }
+
+    pub fn root_mut(&mut self) -> &mut INodeEntry<F> {
+        self.get_mut(ROOT_INODE)
+            .expect("the root inode is always present")
+    }
+
+    /// The number of entries in the table, including the root
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Always `false` in practice, since the root can't be removed
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn contains<T: Into<INode>>(&self, ino: T) -> bool {
+        self.map.contains_key(&ino.into())
+    }
+
+    /// Iterates over every entry in the table, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (INode, &INodeEntry<F>)> {
+        self.map.iter().map(|(ino, entry)| (*ino, entry))
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (INode, &mut INodeEntry<F>)> {
+        self.map.iter_mut().map(|(ino, entry)| (*ino, entry))
+    }
+
+    /// Like `iter`, but only yields the inodes
+    pub fn inodes(&self) -> impl Iterator<Item = INode> + '_ {
+        self.map.keys().copied()
+    }
+
+    /// Returns the parent of `ino` along with its entry. Returns `None` for the root and for
+    /// inodes that aren't in the table.
+    pub fn get_parent(&self, ino: INode) -> Option<(INode, &INodeEntry<F>)> {
+        let parent = self.get(ino)?.parent()?;
+        self.get(parent).map(|entry| (parent, entry))
+    }
+
+    pub fn get_parent_mut(&mut self, ino: INode) -> Option<(INode, &mut INodeEntry<F>)> {
+        let parent = self.get(ino)?.parent()?;
+        self.get_mut(parent).map(|entry| (parent, entry))
+    }
+
+    /// Returns the parent of `ino`, its entry, and the name it has within that parent. Returns
+    /// `None` for the root, since it isn't named by any directory, and for inodes that aren't in
+    /// the table.
+    pub fn get_with_path(&self, ino: INode) -> Option<(INode, &INodeEntry<F>, OsString)> {
+        let entry = self.get(ino)?;
+        let parent = entry.parent()?;
+        let name = entry.name_in_parent(self)?.to_os_string();
+
+        Some((parent, entry, name))
+    }
+
+    /// Returns the absolute path of `ino`, built from the names of it and each of its ancestors.
+    /// The root's path is `/`. Returns `None` if `ino` or any of its ancestors isn't in the
+    /// table.
+    pub fn path_for_inode(&self, ino: INode) -> Option<PathBuf> {
+        let mut names = Vec::new();
+        let mut cur = ino;
+
+        while cur != ROOT_INODE {
+            let (parent, _, name) = self.get_with_path(cur)?;
+            names.push(name);
+            cur = parent;
+        }
+
+        let mut path = PathBuf::from("/");
+        path.extend(names.iter().rev());
+
+        Some(path)
+    }
+
+    /// Returns the number of directory levels between `ino` and the root. The root itself has a
+    /// depth of 0, its direct children a depth of 1 and so on.
+    ///
+    /// Walking stops at the first entry without a parent, so an inode that isn't in the table
+    /// also has a depth of 0.
+    pub fn depth(&self, ino: INode) -> usize {
+        let mut depth = 0;
+        let mut cur = self.get(ino);
+
+        while let Some(parent) = cur.and_then(|x| x.parent()) {
+            depth += 1;
+            cur = self.get(parent);
+        }
+
+        depth
+    }
+
+    /// Computes the POSIX link count of the directory `ino`, which is 2 plus the number of
+    /// subdirectories, and stores it in the directory's attributes. Returns `None` if `ino`
+    /// isn't a directory.
+    pub fn dir_nlink(&mut self, ino: INode) -> Option<u32> {
+        let subdirs = self
+            .get(ino)?
+            .as_dir()?
+            .children()
+            .filter(|(_, child)| self.get(*child).is_some_and(|x| x.as_dir().is_some()))
+            .count();
+
+        let nlink = 2 + subdirs as u32;
+        self.get_mut(ino)?.as_dir_mut()?.attrs.set_nlink(nlink);
+
+        Some(nlink)
+    }
+
+    /// Visits `root` and everything below it in pre-order, with each directory's children
+    /// visited in name order. The visitor is given each inode, its entry, and its depth relative
+    /// to `root`. Returning `false` from the visitor skips that entry's children.
+    pub fn walk<V>(&self, root: INode, visitor: &mut V)
+    where
+        V: FnMut(INode, &INodeEntry<F>, usize) -> bool,
+    {
+        self.walk_from(root, 0, visitor)
+    }
+
+    fn walk_from<V>(&self, ino: INode, depth: usize, visitor: &mut V)
+    where
+        V: FnMut(INode, &INodeEntry<F>, usize) -> bool,
+    {
+        let entry = match self.get(ino) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        if !visitor(ino, entry, depth) {
+            return;
+        }
+
+        if let Some(dir) = entry.as_dir() {
+            let mut children: Vec<_> = dir.children().collect();
+            children.sort_by(|a, b| a.0.cmp(b.0));
+
+            for (_, child) in children {
+                self.walk_from(child, depth + 1, visitor);
+            }
+        }
+    }
+
+    /// Iterates over `root` and everything below it in pre-order, with each directory's children
+    /// visited in name order like `walk`. Hard linked inodes are only yielded the first time
+    /// they're reached.
+    pub fn walk_depth_first(&self, root: INode) -> impl Iterator<Item = (INode, &INodeEntry<F>)> {
+        let mut stack = vec![root];
+        let mut seen = HashSet::new();
+
+        std::iter::from_fn(move || loop {
+            let ino = stack.pop()?;
+            if !seen.insert(ino) {
+                continue;
+            }
+
+            let Some(entry) = self.get(ino) else {
+                continue;
+            };
+
+            // reversed, so the first name is popped first
+            stack.extend(self.sorted_children(entry).rev());
+
+            return Some((ino, entry));
+        })
+    }
+
+    /// Like `walk_depth_first`, but visits everything at one depth before going any deeper
+    pub fn walk_breadth_first(&self, root: INode) -> impl Iterator<Item = (INode, &INodeEntry<F>)> {
+        let mut queue = VecDeque::from([root]);
+        let mut seen = HashSet::new();
+
+        std::iter::from_fn(move || loop {
+            let ino = queue.pop_front()?;
+            if !seen.insert(ino) {
+                continue;
+            }
+
+            let Some(entry) = self.get(ino) else {
+                continue;
+            };
+
+            queue.extend(self.sorted_children(entry));
+
+            return Some((ino, entry));
+        })
+    }
+
+    /// The children of `entry` in name order, or nothing if it isn't a directory
+    fn sorted_children(&self, entry: &INodeEntry<F>) -> std::vec::IntoIter<INode> {
+        let mut children: Vec<_> = entry
+            .as_dir()
+            .into_iter()
+            .flat_map(|x| x.children())
+            .collect();
+        children.sort_by(|a, b| a.0.cmp(b.0));
+
+        let inodes: Vec<_> = children.into_iter().map(|(_, child)| child).collect();
+        inodes.into_iter()
+    }
+
     /// Looks up a path. Will function with or without a leading slash
+    ///
+    /// Paths are normalised the same way on every platform: repeated and trailing slashes are
+    /// ignored, "." refers to the current directory and ".." to its parent (the parent of the
+    /// root being the root itself).
     /// ```
     /// # use simply_fuse::basic::{ROOT_INODE, INodeTable, Directory, INodeEntry};
     /// let mut tbl = INodeTable::<()>::default();
@@ -220,97 +753,405 @@ impl<F> INodeTable<F> {
     ///
     /// assert_eq!(root.0, ROOT_INODE);
     /// assert_eq!(test_dir.0, test_dir_inode);
+    /// assert_eq!(tbl.lookup("//example directory/").unwrap().0, test_dir_inode);
     /// ```
     pub fn lookup<T: AsRef<Path>>(&self, path: T) -> Option<(INode, &INodeEntry<F>)> {
-        let mut parent_ino = ROOT_INODE;
-        let mut parent = self.get(ROOT_INODE)?;
-
-        for component in path.as_ref().components() {
-            let path: &Path = component.as_ref();
-            let path_str = path.to_string_lossy();
-
-            match path_str.as_ref() {
-                "/" if parent_ino == ROOT_INODE => continue, // path starts with "/"
-                _ => {
-                    parent_ino = *parent.as_dir()?.get(path.as_os_str())?;
-                    parent = self.get(parent_ino)?;
-                }
+        let mut ino = ROOT_INODE;
+        let mut entry = self.root();
+
+        // Split on "/" ourselves rather than relying on `Path::components`, so that empty
+        // components are handled the same no matter where they appear
+        for name in path.as_ref().as_os_str().as_bytes().split(|x| *x == b'/') {
+            match name {
+                b"" | b"." => continue,
+                b".." => ino = entry.parent().unwrap_or(ROOT_INODE),
+                name => ino = *entry.as_dir()?.get(OsStr::from_bytes(name))?,
             }
-        }
 
-        let ino = parent_ino;
-        let entry = parent;
+            entry = self.get(ino)?;
+        }
 
         Some((ino, entry))
     }
 
+    /// Like `lookup`, but only returns the inode so the table isn't left borrowed. Useful when
+    /// following up with `get_mut`.
+    pub fn lookup_inode<T: AsRef<Path>>(&self, path: T) -> Option<INode> {
+        self.lookup(path).map(|(ino, _)| ino)
+    }
+
     /// See `lookup` for details
     pub fn lookup_mut<T: AsRef<Path>>(&mut self, path: T) -> Option<(INode, &mut INodeEntry<F>)> {
-        let inode = self.lookup(path).map(|x| x.0);
+        let ino = self.lookup_inode(path)?;
 
-        inode
-            .and_then(|ino| self.get_mut(ino))
-            .map(|x| (inode.unwrap(), x))
+        self.get_mut(ino).map(|entry| (ino, entry))
     }
 
-    fn next_open_inode(&mut self) -> INode {
-        let ino = self.cur_ino;
-        self.cur_ino = ino.next_inode();
-        ino
+    /// Walks `path` like `lookup`, creating any directories that don't exist yet, and returns the
+    /// inode of the last one. This is the equivalent of `mkdir -p`.
+    pub fn lookup_or_create_dir<T: AsRef<Path>>(&mut self, path: T) -> FSResult<INode> {
+        let mut ino = ROOT_INODE;
+
+        for name in path.as_ref().as_os_str().as_bytes().split(|x| *x == b'/') {
+            let name = OsStr::from_bytes(name);
+
+            ino = match name.as_bytes() {
+                b"" | b"." => continue,
+                b".." => self.get(ino).and_then(|x| x.parent()).unwrap_or(ROOT_INODE),
+                _ => match self.get_dir(ino)?.get(name) {
+                    Some(child) => *child,
+                    None => self
+                        .push_entry(ino, name.to_os_string(), Directory::default())
+                        .ok_or(FSError::NotDirectory)?,
+                },
+            };
+
+            // an existing component might not be a directory
+            self.get_dir(ino)?;
+        }
+
+        Ok(ino)
     }
-}
 
-impl<F> Default for INodeTable<F> {
-    fn default() -> INodeTable<F> {
-        let mut h = HashMap::with_capacity(24);
-        h.insert(
-            ROOT_INODE,
-            INodeEntry {
-                parent: None,
-                kind: INodeKind::Directory(Directory::default()),
-            },
-        );
+    /// Removes `ino` from the table and from its parent directory, returning ownership of the
+    /// entry. Directories must be empty, and the root can't be removed.
+    ///
+    /// Hard linked entries are rejected with `FSError::InvalidArgument`, since their other names
+    /// would be left pointing at nothing. Use `unlink_file` for those.
+    pub fn pop_entry(&mut self, ino: INode) -> FSResult<INodeEntry<F>> {
+        let entry = self.get(ino).ok_or(FSError::NoEntry)?;
+        let parent = entry.parent().ok_or(FSError::InvalidArgument)?;
+        let name = entry
+            .name_in_parent(self)
+            .ok_or(FSError::NoEntry)?
+            .to_os_string();
+
+        self.remove(parent, &name).map(|(_, entry)| entry)
+    }
 
-        INodeTable {
-            map: h,
-            cur_ino: ROOT_INODE.next_inode(),
+    /// Removes `name` from `parent`, returning its inode and entry. Like `pop_entry`,
+    /// directories must be empty and hard linked entries are rejected.
+    pub fn remove(&mut self, parent: INode, name: &OsStr) -> FSResult<(INode, INodeEntry<F>)> {
+        let ino = *self.get_dir(parent)?.get(name).ok_or(FSError::NoEntry)?;
+        let entry = self.get(ino).ok_or(FSError::NoEntry)?;
+
+        if entry.as_dir().is_some_and(|dir| !dir.is_empty()) {
+            return Err(FSError::NotEmpty);
+        }
+
+        if entry.link_count() > 1 {
+            return Err(FSError::InvalidArgument);
         }
+
+        self.journal.log_remove(ino);
+
+        // get_dir already checked that the parent is a directory
+        let parent_dir = self.get_mut(parent).and_then(|x| x.as_dir_mut()).unwrap();
+        parent_dir.children.remove(name);
+
+        let entry = self.map.remove(&ino).ok_or(FSError::NoEntry)?;
+        Ok((ino, entry))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Like `remove`, but a directory is removed along with everything below it rather than
+    /// having to be empty, like `rm -rf`. Returns how many entries were dropped from the table.
+    ///
+    /// A hard linked entry only loses the names inside the removed tree, and is kept if it has
+    /// names elsewhere. The tree is walked with an explicit stack, so deep trees can't overflow
+    /// the call stack.
+    pub fn remove_recursive(&mut self, parent: INode, name: &OsStr) -> FSResult<usize> {
+        let ino = *self.get_dir(parent)?.get(name).ok_or(FSError::NoEntry)?;
+
+        let mut removed = 0;
+        let mut stack = vec![(parent, name.to_os_string(), ino)];
+
+        // Only `parent` outlives the walk, so no other directory needs its names taken out
+        while let Some((dir, name, child)) = stack.pop() {
+            let linked = match self.get(child) {
+                Some(entry) => entry.link_count() > 1,
+                None => continue,
+            };
+
+            if linked {
+                self.journal.log_unlink(dir, &name);
+                self.release_link(child, dir);
+                continue;
+            }
 
-    #[derive(Default, Debug)]
-    struct BlankFile {}
+            self.journal.log_remove(child);
+            removed += 1;
 
-    impl IntoINodeEntry<BlankFile> for BlankFile {
-        fn with_parent(self, parent: INode) -> INodeEntry<BlankFile> {
-            INodeEntry {
-                parent: Some(parent),
-                kind: INodeKind::File(self),
+            if let Some(INodeKind::Directory(gone)) = self.map.remove(&child).map(|x| x.kind) {
+                let children = gone.children.into_iter();
+                stack.extend(children.map(|(name, grandchild)| (child, name, grandchild)));
             }
         }
-    }
 
-    fn blank_table() -> INodeTable<BlankFile> {
-        INodeTable::<BlankFile>::default()
+        // get_dir already checked that the parent is a directory
+        let parent_dir = self.get_mut(parent).and_then(|x| x.as_dir_mut()).unwrap();
+        parent_dir.children.remove(name);
+
+        Ok(removed)
     }
 
-    #[test]
-    fn omit_root_slash_lookup() {
-        let mut fs = blank_table();
-        let _ = fs.push_entry(ROOT_INODE, "root file".into(), BlankFile::default());
-        let _ = fs.push_entry(ROOT_INODE, "root dir".into(), Directory::default());
+    /// Moves `name` in `parent` to `new_name` in `new_parent`, replacing the destination if it
+    /// exists. Follows the rules of `rename(2)`: a directory can only replace an empty directory,
+    /// a file can't replace a directory, and a directory can't be moved inside itself.
+    pub fn rename(
+        &mut self,
+        parent: INode,
+        name: &OsStr,
+        new_parent: INode,
+        new_name: &OsStr,
+    ) -> FSResult<()> {
+        let ino = *self.get_dir(parent)?.get(name).ok_or(FSError::NoEntry)?;
+        let replaced = self.get_dir(new_parent)?.get(new_name).copied();
+
+        if replaced == Some(ino) {
+            // both names already refer to the same inode, so there's nothing to do
+            return Ok(());
+        }
 
-        let file = fs.lookup("/root file").unwrap();
+        let mut cur = Some(new_parent);
+        while let Some(ancestor) = cur {
+            if ancestor == ino {
+                return Err(FSError::InvalidArgument);
+            }
 
-        assert_eq!(
-            fs.lookup("root file").unwrap().0,
-            file.0,
-            "omitting / from paths returns different results"
-        );
+            cur = self.get(ancestor).and_then(|x| x.parent());
+        }
+
+        if let Some(replaced) = replaced {
+            let src_is_dir = self.get(ino).and_then(|x| x.as_dir()).is_some();
+            let dest = self.get(replaced).ok_or(FSError::NoEntry)?;
+
+            match (src_is_dir, dest.as_dir()) {
+                (true, Some(dir)) if !dir.is_empty() => return Err(FSError::NotEmpty),
+                (true, None) => return Err(FSError::NotDirectory),
+                (false, Some(_)) => return Err(FSError::IsDirectory),
+                _ => (),
+            }
+
+            // the replaced inode lives on if it has other names
+            if dest.link_count() == 1 {
+                self.journal.log_remove(replaced);
+                self.map.remove(&replaced);
+            } else {
+                self.journal.log_unlink(new_parent, new_name);
+                self.release_link(replaced, new_parent);
+            }
+        }
+
+        self.journal.log_rename(parent, name, new_parent, new_name);
+
+        // The lookups above guarantee both directories exist, so these can't fail
+        let old_dir = self.get_mut(parent).and_then(|x| x.as_dir_mut()).unwrap();
+        old_dir.children.remove(name);
+
+        let new_dir = self
+            .get_mut(new_parent)
+            .and_then(|x| x.as_dir_mut())
+            .unwrap();
+        new_dir.children.insert(new_name.to_os_string(), ino);
+
+        if let Some(entry) = self.get_mut(ino) {
+            // only the name being moved changes directory, not any other hard links
+            if entry.parent == Some(parent) {
+                entry.parent = Some(new_parent);
+            } else if let Some(link) = entry.links.iter_mut().find(|x| **x == parent) {
+                *link = new_parent;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds `new_name` in `new_parent` as another name for `ino`, returning its new link count.
+    /// Directories can't be linked.
+    pub fn link_entry(&mut self, ino: INode, new_parent: INode, new_name: &OsStr) -> FSResult<u32> {
+        if self.get_dir(new_parent)?.get(new_name).is_some() {
+            return Err(FSError::AlreadyExists);
+        }
+
+        let entry = self.get_mut(ino).ok_or(FSError::NoEntry)?;
+
+        if entry.as_dir().is_some() {
+            return Err(FSError::PermissionDenied);
+        }
+
+        if entry.link_count() == u32::MAX {
+            return Err(FSError::TooManyLinks);
+        }
+
+        self.journal.log_link(ino, new_parent, new_name);
+
+        let entry = self.get_mut(ino).unwrap();
+        entry.links.push(new_parent);
+        let nlink = entry.link_count();
+
+        // get_dir already checked that the parent is a directory
+        let new_dir = self
+            .get_mut(new_parent)
+            .and_then(|x| x.as_dir_mut())
+            .unwrap();
+        new_dir.children.insert(new_name.to_os_string(), ino);
+
+        Ok(nlink)
+    }
+
+    /// Removes `name` from `parent`, returning the inode it pointed to and how many names that
+    /// inode has left. Once none are left the entry is also dropped from the table, so any data
+    /// the caller keeps for it elsewhere can be freed.
+    ///
+    /// Directories can't be unlinked.
+    pub fn unlink_file(&mut self, parent: INode, name: &OsStr) -> FSResult<(INode, u32)> {
+        let ino = *self.get_dir(parent)?.get(name).ok_or(FSError::NoEntry)?;
+        let entry = self.get(ino).ok_or(FSError::NoEntry)?;
+
+        if entry.as_dir().is_some() {
+            return Err(FSError::IsDirectory);
+        }
+
+        if entry.link_count() == 1 {
+            self.journal.log_remove(ino);
+        } else {
+            self.journal.log_unlink(parent, name);
+        }
+
+        // get_dir already checked that the parent is a directory
+        let parent_dir = self.get_mut(parent).and_then(|x| x.as_dir_mut()).unwrap();
+        parent_dir.children.remove(name);
+
+        let nlink = self.release_link(ino, parent);
+        if nlink == 0 {
+            self.map.remove(&ino);
+        }
+
+        Ok((ino, nlink))
+    }
+
+    /// Forgets that one of `ino`'s names is in `dir`, without touching `dir` itself, and returns
+    /// how many names are left. If the name was the one `parent` points at, another takes its
+    /// place, so `parent` always refers to a directory which still holds a name.
+    fn release_link(&mut self, ino: INode, dir: INode) -> u32 {
+        let entry = match self.get_mut(ino) {
+            Some(entry) => entry,
+            None => return 0,
+        };
+
+        if let Some(i) = entry.links.iter().position(|x| *x == dir) {
+            entry.links.swap_remove(i);
+        } else if let Some(other) = entry.links.pop() {
+            entry.parent = Some(other);
+        } else {
+            return 0;
+        }
+
+        entry.link_count()
+    }
+
+    fn get_dir(&self, ino: INode) -> FSResult<&Directory> {
+        let entry = self.get(ino).ok_or(FSError::NoEntry)?;
+        entry.as_dir().ok_or(FSError::NotDirectory)
+    }
+
+    fn next_open_inode(&mut self) -> INode {
+        let ino = self.cur_ino;
+        self.cur_ino = ino.next_inode();
+        ino
+    }
+}
+
+impl<F: AttributableMut> INodeTable<F> {
+    /// Like `push_entry`, but applies `attrs` to the entry once it has been inserted. Useful for
+    /// overriding attributes such as the owner at insertion time.
+    pub fn push_entry_with_attrs<E: IntoINodeEntry<F>>(
+        &mut self,
+        parent: INode,
+        name: OsString,
+        entry: E,
+        attrs: SetFileAttributes,
+    ) -> Option<INode> {
+        let ino = self.push_entry(parent, name, entry)?;
+        self.get_mut(ino)?.apply_attrs(attrs);
+
+        Some(ino)
+    }
+}
+
+impl<F> Default for INodeTable<F> {
+    fn default() -> INodeTable<F> {
+        let mut h = HashMap::with_capacity(24);
+        h.insert(
+            ROOT_INODE,
+            INodeEntry {
+                parent: None,
+                links: Vec::new(),
+                kind: INodeKind::Directory(Directory::default()),
+            },
+        );
+
+        INodeTable {
+            map: h,
+            cur_ino: ROOT_INODE.next_inode(),
+            journal: Box::new(NullJournal),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default, Debug)]
+    struct BlankFile {}
+
+    impl IntoINodeEntry<BlankFile> for BlankFile {
+        fn with_parent(self, parent: INode) -> INodeEntry<BlankFile> {
+            INodeEntry {
+                parent: Some(parent),
+                links: Vec::new(),
+                kind: INodeKind::File(self),
+            }
+        }
+    }
+
+    fn blank_table() -> INodeTable<BlankFile> {
+        INodeTable::<BlankFile>::default()
+    }
+
+    #[derive(Debug)]
+    struct AttrFile {
+        attrs: FileAttributes,
+    }
+
+    impl Attributable for AttrFile {
+        fn getattrs(&self) -> FileAttributes {
+            self.attrs
+        }
+    }
+
+    impl AttributableMut for AttrFile {
+        fn setattrs(&mut self, attrs: FileAttributes) {
+            self.attrs = attrs;
+        }
+    }
+
+    impl Filelike for AttrFile {}
+
+    #[test]
+    fn omit_root_slash_lookup() {
+        let mut fs = blank_table();
+        let _ = fs.push_entry(ROOT_INODE, "root file".into(), BlankFile::default());
+        let _ = fs.push_entry(ROOT_INODE, "root dir".into(), Directory::default());
+
+        let file = fs.lookup("/root file").unwrap();
+
+        assert_eq!(
+            fs.lookup("root file").unwrap().0,
+            file.0,
+            "omitting / from paths returns different results"
+        );
 
         assert!(
             file.1.as_file().is_some(),
@@ -356,6 +1197,676 @@ mod tests {
         );
     }
 
+    #[test]
+    fn push_kind_directly() {
+        let mut fs = blank_table();
+
+        let dir = fs
+            .push_entry(
+                ROOT_INODE,
+                "dir".into(),
+                INodeKind::Directory(Directory::default()),
+            )
+            .unwrap();
+
+        let file = fs
+            .push_entry(dir, "file".into(), INodeKind::File(BlankFile::default()))
+            .unwrap();
+
+        assert!(
+            fs.get(dir).unwrap().as_dir().is_some(),
+            "expected directory"
+        );
+        assert!(fs.get(file).unwrap().as_file().is_some(), "expected file");
+        assert_eq!(fs.get(file).unwrap().parent(), Some(dir), "wrong parent");
+    }
+
+    #[test]
+    fn push_entry_applies_attrs() {
+        let mut fs = INodeTable::<AttrFile>::default();
+
+        let attrs = SetFileAttributes::builder()
+            .uid(Some(1000))
+            .gid(Some(100))
+            .build();
+
+        let file = AttrFile {
+            attrs: FileAttributes::builder().mode(libc::S_IFREG).build(),
+        };
+
+        let dir = fs
+            .push_entry_with_attrs(ROOT_INODE, "dir".into(), Directory::default(), attrs)
+            .unwrap();
+
+        let file = fs
+            .push_entry_with_attrs(dir, "file".into(), file, attrs)
+            .unwrap();
+
+        for ino in [dir, file] {
+            let got = fs.get(ino).unwrap().getattrs();
+
+            assert_eq!(got.uid(), 1000, "uid was not applied");
+            assert_eq!(got.gid(), 100, "gid was not applied");
+        }
+
+        assert_eq!(
+            fs.get(file).unwrap().getattrs().mode(),
+            libc::S_IFREG,
+            "unset attributes were changed"
+        );
+    }
+
+    #[test]
+    fn get_with_path_finds_name() {
+        let mut table = blank_table();
+        let dir = table
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+        let file = table.push_entry(dir, "file".into(), BlankFile {}).unwrap();
+
+        let (parent, _, name) = table.get_with_path(file).unwrap();
+        assert_eq!(parent, dir);
+        assert_eq!(name, "file");
+
+        assert!(table.get_with_path(ROOT_INODE).is_none());
+    }
+
+    #[test]
+    fn xattr_store_respects_flags_and_sizes() {
+        let mut store = XAttrStore::default();
+        let name = OsStr::new("user.test");
+
+        assert!(store.set(name, b"value", SetXAttrFlags::Replace).is_err());
+        store.set(name, b"value", SetXAttrFlags::Create).unwrap();
+        assert!(store.set(name, b"other", SetXAttrFlags::Create).is_err());
+        store
+            .set(name, b"value", SetXAttrFlags::CreateOrReplace)
+            .unwrap();
+
+        let other = OsStr::new("user.other");
+        store
+            .set(other, b"new", SetXAttrFlags::CreateOrReplace)
+            .unwrap();
+        assert_eq!(store.get(other), Some(&b"new"[..]));
+        store.remove(other).unwrap();
+
+        assert_eq!(store.get_ref(name, 0).unwrap().full_len(), 5);
+        assert!(store.get_ref(name, 4).is_err());
+        assert_eq!(store.get_ref(name, 5).unwrap().data(), b"value");
+
+        assert_eq!(store.list(0).unwrap().1, 10);
+        assert_eq!(store.list(10).unwrap().0, "user.test\0");
+
+        store.remove(name).unwrap();
+        assert!(store.get(name).is_none());
+    }
+
+    #[test]
+    fn remove_recursive_removes_subtree() {
+        let mut tbl = blank_table();
+        let dir = tbl.lookup_or_create_dir("a/b/c").unwrap();
+        let file = tbl
+            .push_entry(dir, "file".into(), BlankFile::default())
+            .unwrap();
+
+        assert!(matches!(
+            tbl.remove(ROOT_INODE, OsStr::new("a")),
+            Err(FSError::NotEmpty)
+        ));
+
+        let a = tbl.lookup_inode("a").unwrap();
+        assert_eq!(
+            tbl.remove_recursive(ROOT_INODE, OsStr::new("a")).unwrap(),
+            4
+        );
+        assert!(tbl.get(a).is_none());
+        assert!(tbl.get(dir).is_none());
+        assert!(tbl.get(file).is_none());
+        assert!(tbl.root().as_dir().unwrap().is_empty());
+    }
+
+    #[test]
+    fn path_for_inode_builds_absolute_path() {
+        let mut tbl = blank_table();
+        let dir = tbl.lookup_or_create_dir("a/b").unwrap();
+        let file = tbl
+            .push_entry(dir, "file".into(), BlankFile::default())
+            .unwrap();
+
+        assert_eq!(tbl.path_for_inode(ROOT_INODE).unwrap(), Path::new("/"));
+        assert_eq!(tbl.path_for_inode(dir).unwrap(), Path::new("/a/b"));
+        assert_eq!(tbl.path_for_inode(file).unwrap(), Path::new("/a/b/file"));
+        assert!(tbl.path_for_inode(INode::from(1000u64)).is_none());
+    }
+
+    #[test]
+    fn len_counts_root() {
+        let mut tbl = blank_table();
+        assert_eq!(tbl.len(), 1);
+        assert!(!tbl.is_empty());
+        assert!(tbl.contains(ROOT_INODE));
+
+        let file = tbl
+            .push_entry(ROOT_INODE, "file".into(), BlankFile::default())
+            .unwrap();
+        assert_eq!(tbl.len(), 2);
+        assert!(tbl.contains(file));
+
+        tbl.pop_entry(file).unwrap();
+        assert_eq!(tbl.len(), 1);
+        assert!(!tbl.contains(file));
+    }
+
+    #[test]
+    fn iter_visits_every_entry() {
+        let mut tbl = blank_table();
+        let dir = tbl.lookup_or_create_dir("a").unwrap();
+        let file = tbl
+            .push_entry(dir, "file".into(), BlankFile::default())
+            .unwrap();
+
+        let mut inodes: Vec<_> = tbl.inodes().collect();
+        inodes.sort_by_key(|x| x.to_u64());
+        assert_eq!(inodes, [ROOT_INODE, dir, file]);
+
+        assert_eq!(tbl.iter().filter(|(_, x)| x.as_dir().is_some()).count(), 2);
+
+        for (_, entry) in tbl.iter_mut() {
+            entry.parent = None;
+        }
+        assert!(tbl.iter().all(|(_, x)| x.parent().is_none()));
+    }
+
+    #[test]
+    fn walks_visit_hard_links_once() {
+        let mut tbl = INodeTable::<AttrFile>::default();
+        let a = tbl.lookup_or_create_dir("a").unwrap();
+        let b = tbl.lookup_or_create_dir("b").unwrap();
+        let nested = tbl.lookup_or_create_dir("a/nested").unwrap();
+        let attrs = FileAttributes::builder().mode(libc::S_IFREG).build();
+        let file = tbl.push_file(a, "file".into(), AttrFile { attrs }).unwrap();
+        tbl.link_entry(file, b, OsStr::new("link")).unwrap();
+
+        let depth: Vec<_> = tbl.walk_depth_first(ROOT_INODE).map(|x| x.0).collect();
+        assert_eq!(depth, [ROOT_INODE, a, file, nested, b]);
+
+        let breadth: Vec<_> = tbl.walk_breadth_first(ROOT_INODE).map(|x| x.0).collect();
+        assert_eq!(breadth, [ROOT_INODE, a, b, file, nested]);
+    }
+
+    #[test]
+    fn pop_entry_returns_entry() {
+        let mut table = blank_table();
+        let dir = table
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+        let file = table.push_entry(dir, "file".into(), BlankFile {}).unwrap();
+
+        assert!(matches!(table.pop_entry(dir), Err(FSError::NotEmpty)));
+        assert!(table.pop_entry(ROOT_INODE).is_err());
+
+        let entry = table.pop_entry(file).unwrap();
+        assert!(entry.as_file().is_some());
+        assert_eq!(table.lookup_inode("/dir/file"), None);
+        assert!(table.root().as_dir().unwrap().get("dir".as_ref()).is_some());
+    }
+
+    #[test]
+    fn rename_within_directory() {
+        let mut table = blank_table();
+        let file = table
+            .push_entry(ROOT_INODE, "old".into(), BlankFile {})
+            .unwrap();
+
+        table
+            .rename(ROOT_INODE, "old".as_ref(), ROOT_INODE, "new".as_ref())
+            .unwrap();
+
+        assert_eq!(table.lookup_inode("/new"), Some(file));
+        assert_eq!(table.lookup_inode("/old"), None);
+    }
+
+    #[test]
+    fn rename_between_directories() {
+        let mut table = blank_table();
+        let dir = table
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+        let file = table
+            .push_entry(ROOT_INODE, "file".into(), BlankFile {})
+            .unwrap();
+
+        table
+            .rename(ROOT_INODE, "file".as_ref(), dir, "file".as_ref())
+            .unwrap();
+
+        assert_eq!(table.lookup_inode("/dir/file"), Some(file));
+        assert_eq!(table.get(file).unwrap().parent(), Some(dir));
+        assert_eq!(table.lookup_inode("/file"), None);
+    }
+
+    #[test]
+    fn rename_replaces_destination() {
+        let mut table = blank_table();
+        let src = table
+            .push_entry(ROOT_INODE, "src".into(), BlankFile {})
+            .unwrap();
+        let dest = table
+            .push_entry(ROOT_INODE, "dest".into(), BlankFile {})
+            .unwrap();
+
+        table
+            .rename(ROOT_INODE, "src".as_ref(), ROOT_INODE, "dest".as_ref())
+            .unwrap();
+
+        assert_eq!(table.lookup_inode("/dest"), Some(src));
+        assert!(table.get(dest).is_none());
+    }
+
+    #[test]
+    fn rename_refuses_non_empty_directory() {
+        let mut table = blank_table();
+        table
+            .push_entry(ROOT_INODE, "src".into(), Directory::default())
+            .unwrap();
+        let dest = table
+            .push_entry(ROOT_INODE, "dest".into(), Directory::default())
+            .unwrap();
+        table
+            .push_entry(dest, "child".into(), BlankFile {})
+            .unwrap();
+
+        let result = table.rename(ROOT_INODE, "src".as_ref(), ROOT_INODE, "dest".as_ref());
+
+        assert!(matches!(result, Err(FSError::NotEmpty)));
+        assert_eq!(table.lookup_inode("/dest"), Some(dest));
+    }
+
+    #[test]
+    fn push_symlink_keeps_target() {
+        let mut table = blank_table();
+        let link = table
+            .push_symlink(ROOT_INODE, "link".into(), "/some/target".into())
+            .unwrap();
+
+        let link = table.get(link).unwrap().as_symlink().unwrap();
+        assert_eq!(link.target(), "/some/target");
+        assert_eq!(link.getattrs().mode(), libc::S_IFLNK | 0o777);
+    }
+
+    #[test]
+    fn unlink_file_drops_last_link() {
+        let mut table = INodeTable::<AttrFile>::default();
+        let attrs = FileAttributes::builder()
+            .mode(libc::S_IFREG)
+            .nlink(1u32)
+            .build();
+        let file = table
+            .push_file(ROOT_INODE, "file".into(), AttrFile { attrs })
+            .unwrap();
+
+        assert_eq!(
+            table.unlink_file(ROOT_INODE, "file".as_ref()).unwrap(),
+            (file, 0)
+        );
+        assert!(table.get(file).is_none());
+        assert_eq!(table.lookup_inode("/file"), None);
+
+        let dir = table
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+        assert!(table.unlink_file(ROOT_INODE, "dir".as_ref()).is_err());
+        assert!(table.get(dir).is_some());
+    }
+
+    #[test]
+    fn link_entry_shares_inode() {
+        let mut table = INodeTable::<AttrFile>::default();
+        let attrs = FileAttributes::builder()
+            .mode(libc::S_IFREG)
+            .nlink(1u32)
+            .build();
+        let file = table
+            .push_file(ROOT_INODE, "file".into(), AttrFile { attrs })
+            .unwrap();
+
+        assert_eq!(
+            table.link_entry(file, ROOT_INODE, "link".as_ref()).unwrap(),
+            2
+        );
+        assert_eq!(table.lookup_inode("/link"), Some(file));
+
+        assert_eq!(
+            table.unlink_file(ROOT_INODE, "file".as_ref()).unwrap(),
+            (file, 1)
+        );
+        assert!(table.get(file).is_some());
+    }
+
+    #[test]
+    fn unlinking_one_name_keeps_the_others() {
+        let mut table = INodeTable::<AttrFile>::default();
+        // nlink deliberately left at 0, the table keeps the count itself
+        let attrs = FileAttributes::builder().mode(libc::S_IFREG).build();
+        let file = table
+            .push_file(ROOT_INODE, "file".into(), AttrFile { attrs })
+            .unwrap();
+        let dir = table.lookup_or_create_dir("dir").unwrap();
+
+        assert_eq!(table.get(file).unwrap().getattrs().nlink(), 1);
+        assert_eq!(table.link_entry(file, dir, "link".as_ref()).unwrap(), 2);
+
+        assert_eq!(
+            table.unlink_file(ROOT_INODE, "file".as_ref()).unwrap(),
+            (file, 1)
+        );
+        assert_eq!(table.get(file).unwrap().getattrs().nlink(), 1);
+        assert_eq!(table.get(file).unwrap().parent(), Some(dir));
+        assert_eq!(table.path_for_inode(file).unwrap(), Path::new("/dir/link"));
+
+        // what readdir does: every listed name must still resolve
+        for (_, child) in table.get(dir).unwrap().as_dir().unwrap().children() {
+            assert!(table.get(child).is_some());
+        }
+
+        assert_eq!(table.unlink_file(dir, "link".as_ref()).unwrap(), (file, 0));
+        assert!(table.get(file).is_none());
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct RecordingJournal(std::sync::Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl RecordingJournal {
+        fn push(&self, line: String) {
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    impl<F> Journal<F> for RecordingJournal {
+        fn log_insert(&mut self, ino: INode, _name: &OsStr, _entry: &INodeEntry<F>) {
+            self.push(format!("insert {}", ino.to_u64()));
+        }
+
+        fn log_remove(&mut self, ino: INode) {
+            self.push(format!("remove {}", ino.to_u64()));
+        }
+
+        fn log_rename(&mut self, _parent: INode, name: &OsStr, _new: INode, new_name: &OsStr) {
+            self.push(format!("rename {:?} {:?}", name, new_name));
+        }
+
+        fn log_link(&mut self, ino: INode, _new_parent: INode, new_name: &OsStr) {
+            self.push(format!("link {} {:?}", ino.to_u64(), new_name));
+        }
+
+        fn log_unlink(&mut self, _parent: INode, name: &OsStr) {
+            self.push(format!("unlink {:?}", name));
+        }
+    }
+
+    #[test]
+    fn journal_records_links() {
+        let journal = RecordingJournal::default();
+        let mut table = INodeTable::<AttrFile>::with_journal(Box::new(journal.clone()));
+        let attrs = FileAttributes::builder().mode(libc::S_IFREG).build();
+        let file = table
+            .push_file(ROOT_INODE, "file".into(), AttrFile { attrs })
+            .unwrap();
+
+        table.link_entry(file, ROOT_INODE, "link".as_ref()).unwrap();
+        table.unlink_file(ROOT_INODE, "file".as_ref()).unwrap();
+        table.unlink_file(ROOT_INODE, "link".as_ref()).unwrap();
+
+        let n = file.to_u64();
+        assert_eq!(
+            *journal.0.lock().unwrap(),
+            [
+                format!("insert {}", n),
+                format!("link {} \"link\"", n),
+                "unlink \"file\"".to_string(),
+                format!("remove {}", n),
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_rejects_hard_links() {
+        let mut table = INodeTable::<AttrFile>::default();
+        let attrs = FileAttributes::builder().mode(libc::S_IFREG).build();
+        let dir = table.lookup_or_create_dir("a").unwrap();
+        let other = table.lookup_or_create_dir("b").unwrap();
+        let file = table
+            .push_file(dir, "file".into(), AttrFile { attrs })
+            .unwrap();
+        table.link_entry(file, other, "link".as_ref()).unwrap();
+
+        assert!(matches!(
+            table.remove(other, "link".as_ref()),
+            Err(FSError::InvalidArgument)
+        ));
+        assert!(table.pop_entry(file).is_err());
+        assert_eq!(table.lookup_inode("a/file"), Some(file));
+        assert_eq!(table.lookup_inode("b/link"), Some(file));
+
+        table.unlink_file(dir, "file".as_ref()).unwrap();
+        let (ino, _) = table.remove(other, "link".as_ref()).unwrap();
+        assert_eq!(ino, file);
+        assert!(table.get(file).is_none());
+        assert!(table.get(other).unwrap().as_dir().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rename_keeps_hard_links_intact() {
+        let mut table = INodeTable::<AttrFile>::default();
+        let attrs = FileAttributes::builder().mode(libc::S_IFREG).build();
+        let a = table.lookup_or_create_dir("a").unwrap();
+        let b = table.lookup_or_create_dir("b").unwrap();
+        let c = table.lookup_or_create_dir("c").unwrap();
+        let file = table
+            .push_file(a, "file".into(), AttrFile { attrs })
+            .unwrap();
+        let other = table
+            .push_file(c, "other".into(), AttrFile { attrs })
+            .unwrap();
+        table.link_entry(file, b, "link".as_ref()).unwrap();
+
+        // replacing one name of a linked file leaves the other
+        table
+            .rename(c, "other".as_ref(), b, "link".as_ref())
+            .unwrap();
+        assert_eq!(table.lookup_inode("b/link"), Some(other));
+        assert_eq!(table.lookup_inode("a/file"), Some(file));
+        assert_eq!(table.get(file).unwrap().getattrs().nlink(), 1);
+        assert_eq!(table.path_for_inode(file).unwrap(), Path::new("/a/file"));
+
+        // moving a name that isn't the one parent points at leaves parent alone
+        table.link_entry(file, b, "link2".as_ref()).unwrap();
+        table
+            .rename(b, "link2".as_ref(), c, "moved".as_ref())
+            .unwrap();
+        assert_eq!(table.get(file).unwrap().parent(), Some(a));
+
+        table.unlink_file(a, "file".as_ref()).unwrap();
+        assert_eq!(table.path_for_inode(file).unwrap(), Path::new("/c/moved"));
+    }
+
+    #[test]
+    fn remove_recursive_keeps_links_outside_the_tree() {
+        let mut table = INodeTable::<AttrFile>::default();
+        let attrs = FileAttributes::builder().mode(libc::S_IFREG).build();
+        let dir = table.lookup_or_create_dir("a/b").unwrap();
+        let file = table
+            .push_file(dir, "file".into(), AttrFile { attrs })
+            .unwrap();
+        let inner = table
+            .push_file(dir, "inner".into(), AttrFile { attrs })
+            .unwrap();
+        table
+            .link_entry(file, ROOT_INODE, "outside".as_ref())
+            .unwrap();
+        table.link_entry(inner, dir, "inner2".as_ref()).unwrap();
+
+        // a, b and inner go, file survives through its other name
+        assert_eq!(table.remove_recursive(ROOT_INODE, "a".as_ref()).unwrap(), 3);
+        assert!(table.get(inner).is_none());
+        assert_eq!(table.lookup_inode("outside"), Some(file));
+        assert_eq!(table.get(file).unwrap().parent(), Some(ROOT_INODE));
+        assert_eq!(table.get(file).unwrap().getattrs().nlink(), 1);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn depth_counts_parents() {
+        let mut fs = blank_table();
+
+        let dir = fs
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+
+        let file = fs
+            .push_entry(dir, "file".into(), BlankFile::default())
+            .unwrap();
+
+        assert_eq!(fs.depth(ROOT_INODE), 0, "root is not at depth 0");
+        assert_eq!(fs.depth(dir), 1, "child of root is not at depth 1");
+        assert_eq!(fs.depth(file), 2, "grandchild of root is not at depth 2");
+    }
+
+    #[test]
+    fn dir_nlink_counts_subdirectories() {
+        let mut table = blank_table();
+        table
+            .push_entry(ROOT_INODE, "dir1".into(), Directory::default())
+            .unwrap();
+        table
+            .push_entry(ROOT_INODE, "dir2".into(), Directory::default())
+            .unwrap();
+        table
+            .push_entry(ROOT_INODE, "file".into(), BlankFile {})
+            .unwrap();
+
+        assert_eq!(table.dir_nlink(ROOT_INODE), Some(4));
+        assert_eq!(table.root().as_dir().unwrap().getattrs().nlink(), 4);
+    }
+
+    #[test]
+    fn walk_visits_in_pre_order() {
+        let mut table = blank_table();
+        let b = table.lookup_or_create_dir("/b").unwrap();
+        let a = table.lookup_or_create_dir("/a").unwrap();
+        let a_child = table.lookup_or_create_dir("/a/child").unwrap();
+        table.lookup_or_create_dir("/b/skipped").unwrap();
+
+        let mut visited = Vec::new();
+        table.walk(ROOT_INODE, &mut |ino, _, depth| {
+            visited.push((ino, depth));
+            ino != b
+        });
+
+        assert_eq!(visited, [(ROOT_INODE, 0), (a, 1), (a_child, 2), (b, 1)]);
+    }
+
+    #[test]
+    fn directory_child_count() {
+        let mut fs = blank_table();
+
+        let dir = fs
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+
+        assert!(
+            fs.get(dir).unwrap().as_dir().unwrap().is_empty(),
+            "new directory is not empty"
+        );
+
+        let _ = fs.push_entry(dir, "file1".into(), BlankFile::default());
+        let _ = fs.push_entry(dir, "file2".into(), BlankFile::default());
+
+        let dir = fs.get(dir).unwrap().as_dir().unwrap();
+        assert_eq!(dir.child_count(), 2, "directory child count is wrong");
+        assert!(!dir.is_empty(), "directory with children is empty");
+    }
+
+    #[test]
+    fn readdir_check_requires_dots() {
+        let entry = |name: &str, offset| {
+            DirEntry::builder()
+                .name(name.into())
+                .inode(ROOT_INODE)
+                .typ(FileType::Directory)
+                .offset(offset)
+                .build()
+        };
+
+        assert!(
+            check_readdir_entries(&[]).is_err(),
+            "empty listing accepted"
+        );
+        assert!(
+            check_readdir_entries(&[entry(".", 1)]).is_err(),
+            "listing without \"..\" accepted"
+        );
+        assert!(
+            check_readdir_entries(&[entry(".", 0), entry("..", 1)]).is_err(),
+            "dots at the wrong offsets accepted"
+        );
+        assert!(
+            check_readdir_entries(&[entry(".", 1), entry("..", 2), entry("file", 3)]).is_ok(),
+            "valid listing rejected"
+        );
+    }
+
+    #[test]
+    fn lookup_or_create_dir_is_idempotent() {
+        let mut table = blank_table();
+        let dir = table.lookup_or_create_dir("/a/b/c").unwrap();
+
+        assert_eq!(table.lookup_inode("/a/b/c"), Some(dir));
+        assert_eq!(table.lookup_or_create_dir("a/b/c/").unwrap(), dir);
+
+        table.push_entry(dir, "file".into(), BlankFile {}).unwrap();
+        assert!(table.lookup_or_create_dir("/a/b/c/file/d").is_err());
+    }
+
+    #[test]
+    fn lookup_normalises_slashes() {
+        let mut fs = blank_table();
+
+        let dir1 = fs
+            .push_entry(ROOT_INODE, "dir1".into(), Directory::default())
+            .unwrap();
+
+        let dir2 = fs
+            .push_entry(dir1, "dir2".into(), Directory::default())
+            .unwrap();
+
+        macro_rules! check {
+            ($path:expr, $expected:expr) => {
+                assert_eq!(
+                    fs.lookup($path).map(|x| x.0),
+                    Some($expected),
+                    concat!(stringify!($path), " did not resolve correctly")
+                )
+            };
+        }
+
+        check!("", ROOT_INODE);
+        check!("/", ROOT_INODE);
+        check!("///", ROOT_INODE);
+        check!("//dir1", dir1);
+        check!("dir1/", dir1);
+        check!("///dir1//dir2", dir2);
+        check!("/dir1/dir2//", dir2);
+        check!("./dir1/./dir2", dir2);
+        check!("dir1/dir2/..", dir1);
+        check!("/..", ROOT_INODE);
+
+        assert!(
+            fs.lookup("dir1//missing").is_none(),
+            "missing path resolved"
+        );
+    }
+
     /// This test should never fail. If it does, we likely have some much bigger problems somewhere
     #[test]
     fn ensure_lookup_equals_lookup_mut() {
@@ -412,3 +1923,164 @@ mod tests {
         ];
     }
 }
+
+#[cfg(test)]
+mod integration_tests {
+    use super::*;
+    use crate::error::FSError;
+
+    fn table_with_file() -> (INodeTable<()>, INode, INode) {
+        let mut table = INodeTable::default();
+        let dir = table
+            .push_entry(ROOT_INODE, "dir".into(), Directory::default())
+            .unwrap();
+        let file = table
+            .push_entry(dir, "file".into(), INodeKind::File(()))
+            .unwrap();
+
+        (table, dir, file)
+    }
+
+    #[test]
+    fn rename_missing_source_fails() {
+        let (mut table, dir, _) = table_with_file();
+
+        let result = table.rename(dir, "missing".as_ref(), dir, "other".as_ref());
+        assert!(matches!(result, Err(FSError::NoEntry)));
+    }
+
+    #[test]
+    fn rename_file_over_directory_fails() {
+        let (mut table, dir, file) = table_with_file();
+
+        let result = table.rename(dir, "file".as_ref(), ROOT_INODE, "dir".as_ref());
+        assert!(result.is_err());
+        assert_eq!(table.lookup_inode("/dir/file"), Some(file));
+    }
+
+    #[test]
+    fn rename_directory_into_itself_fails() {
+        let (mut table, dir, _) = table_with_file();
+        let child = table
+            .push_entry(dir, "child".into(), Directory::default())
+            .unwrap();
+
+        let result = table.rename(ROOT_INODE, "dir".as_ref(), child, "dir".as_ref());
+        assert!(matches!(result, Err(FSError::InvalidArgument)));
+        assert_eq!(table.get(dir).unwrap().parent(), Some(ROOT_INODE));
+    }
+
+    #[test]
+    fn missing_inodes_are_handled() {
+        let (table, _, _) = table_with_file();
+        let missing = INode::from(1000);
+
+        assert!(table.get(missing).is_none());
+        assert!(table.get_parent(missing).is_none());
+        assert!(table.get_with_path(missing).is_none());
+        assert_eq!(table.depth(missing), 0);
+    }
+
+    #[test]
+    fn table_with_many_entries() {
+        let mut table = INodeTable::<()>::default();
+        let mut inodes = Vec::new();
+
+        for i in 0..1000 {
+            let name = OsString::from(format!("file{}", i));
+            let ino = table
+                .push_entry(ROOT_INODE, name, INodeKind::File(()))
+                .unwrap();
+
+            inodes.push(ino);
+        }
+
+        assert_eq!(table.root().as_dir().unwrap().child_count(), 1000);
+
+        for (i, ino) in inodes.iter().enumerate() {
+            assert_eq!(table.lookup_inode(format!("/file{}", i)), Some(*ino));
+            assert_eq!(table.depth(*ino), 1);
+        }
+
+        assert_eq!(table.iter().count(), 1001);
+        let mut seen: Vec<_> = table.inodes().filter(|x| *x != ROOT_INODE).collect();
+        seen.sort_by_key(|x| x.to_u64());
+        assert_eq!(seen, inodes);
+    }
+
+    #[test]
+    fn remove_missing_entry_fails() {
+        let (mut table, dir, _) = table_with_file();
+
+        let result = table.remove(dir, "missing".as_ref());
+        assert!(matches!(result, Err(FSError::NoEntry)));
+        assert!(matches!(
+            table.remove_recursive(dir, "missing".as_ref()),
+            Err(FSError::NoEntry)
+        ));
+        assert!(matches!(
+            table.pop_entry(INode::from(1000)),
+            Err(FSError::NoEntry)
+        ));
+        assert_eq!(table.len(), 3);
+    }
+
+    #[test]
+    fn iter_on_empty_table_yields_root() {
+        let table = INodeTable::<()>::default();
+
+        let entries: Vec<_> = table.iter().map(|(ino, _)| ino).collect();
+        assert_eq!(entries, [ROOT_INODE]);
+        assert_eq!(table.inodes().collect::<Vec<_>>(), [ROOT_INODE]);
+    }
+
+    #[test]
+    fn rename_over_existing_name_replaces() {
+        let (mut table, dir, file) = table_with_file();
+        let other = table
+            .push_entry(ROOT_INODE, "other".into(), INodeKind::File(()))
+            .unwrap();
+
+        table
+            .rename(ROOT_INODE, "other".as_ref(), dir, "file".as_ref())
+            .unwrap();
+        assert_eq!(table.lookup_inode("/dir/file"), Some(other));
+        assert!(table.get(file).is_none());
+        assert!(table.lookup_inode("/other").is_none());
+    }
+
+    #[test]
+    fn hard_link_to_directory_fails() {
+        let (mut table, dir, _) = table_with_file();
+
+        let result = table.link_entry(dir, ROOT_INODE, "link".as_ref());
+        assert!(matches!(result, Err(FSError::PermissionDenied)));
+        assert!(table.lookup_inode("/link").is_none());
+    }
+
+    #[test]
+    fn hard_links_survive_remove_and_rename() {
+        let (mut table, dir, file) = table_with_file();
+        table.link_entry(file, ROOT_INODE, "link".as_ref()).unwrap();
+
+        // remove refuses to leave the other name dangling
+        assert!(table.remove(ROOT_INODE, "link".as_ref()).is_err());
+        assert_eq!(table.lookup_inode("/dir/file"), Some(file));
+
+        // replacing one name keeps the inode alive under the other
+        let other = table
+            .push_entry(ROOT_INODE, "other".into(), INodeKind::File(()))
+            .unwrap();
+        table
+            .rename(ROOT_INODE, "other".as_ref(), dir, "file".as_ref())
+            .unwrap();
+        assert_eq!(table.lookup_inode("/dir/file"), Some(other));
+        assert_eq!(table.lookup_inode("/link"), Some(file));
+        assert_eq!(table.get(file).unwrap().link_count(), 1);
+        assert_eq!(table.get(file).unwrap().parent(), Some(ROOT_INODE));
+
+        // the last name can now be removed normally
+        table.remove(ROOT_INODE, "link".as_ref()).unwrap();
+        assert!(!table.contains(file));
+    }
+}
@@ -0,0 +1,426 @@
+use crate::basic::{check_readdir_entries, ROOT_INODE};
+use crate::error::{FSError, FSResult};
+use crate::{Filesystem, INode, OpKind, Runner};
+
+use std::collections::{HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+/// How much data a dry run asks for when reading a file
+const DRY_RUN_READ_SIZE: u32 = 4096;
+
+/// The outcome of a single operation performed during a dry run
+#[derive(Debug)]
+pub enum DryRunOutcome {
+    /// The filesystem returned successfully
+    Ok,
+    /// The filesystem returned an error, which would have been sent to the kernel
+    Err(FSError),
+    /// The reply would have been rejected, or is inconsistent with an earlier reply
+    Invalid(&'static str),
+    /// The filesystem panicked
+    Panicked,
+}
+
+#[derive(Debug)]
+pub struct DryRunOp {
+    pub op: OpKind,
+    pub inode: INode,
+    pub outcome: DryRunOutcome,
+}
+
+impl DryRunOp {
+    /// Whether this operation would have broken a mounted filesystem. Errors are not failures,
+    /// since they are a perfectly valid reply.
+    pub fn is_failure(&self) -> bool {
+        matches!(
+            self.outcome,
+            DryRunOutcome::Invalid(_) | DryRunOutcome::Panicked
+        )
+    }
+}
+
+/// Every operation performed during a dry run, in the order they were performed
+#[derive(Debug, Default)]
+pub struct DryRunReport {
+    pub ops: Vec<DryRunOp>,
+}
+
+impl DryRunReport {
+    pub fn failures(&self) -> impl Iterator<Item = &DryRunOp> {
+        self.ops.iter().filter(|op| op.is_failure())
+    }
+
+    /// Returns true if no operation panicked or produced an invalid reply
+    pub fn is_ok(&self) -> bool {
+        self.failures().next().is_none()
+    }
+
+    fn record(&mut self, op: OpKind, inode: INode, outcome: DryRunOutcome) {
+        self.ops.push(DryRunOp { op, inode, outcome });
+    }
+}
+
+impl<T: Filesystem> Runner<T> {
+    /// Validates `fs` without mounting it. See `Runner::run_dry` for what is checked.
+    pub fn dry_run<P: AsRef<Path>>(fs: T, mountpoint: P) -> DryRunReport {
+        Runner::new(fs, mountpoint).run_dry()
+    }
+
+    /// Walks the filesystem from the root the same way the kernel would, without mounting it.
+    /// Every directory is opened, listed, has its children looked up and is released, every file
+    /// is opened, read from and released, and every symlink is read. Once the walk is done, every
+    /// lookup is forgotten again, so the filesystem is left as it was for a real mount.
+    ///
+    /// Only the `Filesystem` methods are exercised, through the same timeout policy, metrics and
+    /// `pre_op`/`post_op` hooks as `run_block`. The runner's `handle_*` methods need a kernel
+    /// request to answer, so reply encoding, size limits on replies and the routing of `setattr`
+    /// and writes aren't covered.
+    pub fn run_dry(&mut self) -> DryRunReport {
+        let mut report = DryRunReport::default();
+        let mut visited = HashSet::new();
+        let mut lookups = HashMap::new();
+        let mut pending = vec![ROOT_INODE];
+
+        while let Some(ino) = pending.pop() {
+            if !visited.insert(ino) {
+                continue;
+            }
+
+            let attrs = match self.exercise(&mut report, OpKind::Getattr, ino, |fs| fs.getattr(ino))
+            {
+                Some(attrs) => attrs,
+                None => continue,
+            };
+
+            if attrs.mode() & libc::S_IFMT == libc::S_IFDIR {
+                self.walk_dir(&mut report, ino, &mut pending, &mut lookups);
+                continue;
+            }
+
+            if attrs.mode() & libc::S_IFMT == libc::S_IFLNK {
+                self.exercise(&mut report, OpKind::Readlink, ino, |fs| {
+                    fs.readlink(ino).map(|_| ())
+                });
+                continue;
+            }
+
+            let open = self.exercise(&mut report, OpKind::Open, ino, |fs| fs.open(ino, 0));
+
+            let read = self.exercise(&mut report, OpKind::Read, ino, |fs| {
+                fs.read(ino, 0, DRY_RUN_READ_SIZE).map(|buf| buf.len())
+            });
+
+            if read.is_some_and(|len| len > DRY_RUN_READ_SIZE as usize) {
+                report.record(
+                    OpKind::Read,
+                    ino,
+                    DryRunOutcome::Invalid("read returned more data than requested"),
+                );
+            }
+
+            if let Some(open) = open {
+                self.exercise(&mut report, OpKind::Release, ino, |fs| {
+                    fs.release(ino, open.handle, 0, false)
+                });
+            }
+        }
+
+        // The kernel forgets everything it looked up when it unmounts
+        for (ino, nlookup) in lookups {
+            self.exercise(&mut report, OpKind::Forget, ino, |fs| {
+                fs.forget(ino, nlookup);
+                Ok(())
+            });
+        }
+
+        report
+    }
+
+    fn walk_dir(
+        &mut self,
+        report: &mut DryRunReport,
+        ino: INode,
+        pending: &mut Vec<INode>,
+        lookups: &mut HashMap<INode, u64>,
+    ) {
+        let open = self.exercise(report, OpKind::Opendir, ino, |fs| fs.open_dir(ino, 0));
+        let entries = self.exercise(report, OpKind::Readdir, ino, |fs| fs.readdir(ino, 0));
+
+        if let Some(open) = open {
+            self.exercise(report, OpKind::Releasedir, ino, |fs| {
+                fs.releasedir(ino, open.handle, 0)
+            });
+        }
+
+        let entries = match entries {
+            Some(entries) => entries,
+            None => return,
+        };
+
+        if let Err(e) = check_readdir_entries(&entries) {
+            report.record(OpKind::Readdir, ino, DryRunOutcome::Invalid(e));
+        }
+
+        for entry in entries {
+            if entry.name == "." || entry.name == ".." {
+                continue;
+            }
+
+            let lookup = self.exercise(report, OpKind::Lookup, ino, |fs| {
+                fs.lookup(ino, &entry.name)
+            });
+
+            if let Some(lookup) = lookup {
+                *lookups.entry(lookup.inode).or_default() += 1;
+
+                if lookup.inode != entry.inode {
+                    report.record(
+                        OpKind::Lookup,
+                        ino,
+                        DryRunOutcome::Invalid("lookup disagrees with readdir on the inode"),
+                    );
+                }
+
+                pending.push(lookup.inode);
+            }
+        }
+    }
+
+    /// Runs `f` through the same path as a real request, recording how it went. Returns the
+    /// result only if the filesystem succeeded.
+    fn exercise<R>(
+        &mut self,
+        report: &mut DryRunReport,
+        op: OpKind,
+        ino: INode,
+        f: impl FnOnce(&mut T) -> FSResult<R, T::Error>,
+    ) -> Option<R> {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.call(op, f)));
+
+        let (outcome, value) = match result {
+            Ok(Ok(value)) => (DryRunOutcome::Ok, Some(value)),
+            Ok(Err(e)) => (DryRunOutcome::Err(e), None),
+            Err(_) => (DryRunOutcome::Panicked, None),
+        };
+
+        report.record(op, ino, outcome);
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attrs::FileAttributes;
+    use crate::{DirEntry, FileType, Filehandle, Lookup, OpenDir, OpenFile};
+
+    use std::ffi::OsStr;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use std::sync::Arc;
+
+    const FILE_INODE: INode = INode(2);
+
+    /// Handles not yet released and lookups not yet forgotten
+    #[derive(Default)]
+    struct Outstanding {
+        handles: AtomicI64,
+        lookups: AtomicI64,
+    }
+
+    /// A root directory holding a single file, with a switch for each mistake a dry run catches
+    #[derive(Default)]
+    struct OneFileFS {
+        long_read: bool,
+        wrong_inode: bool,
+        missing_dots: bool,
+        data: Vec<u8>,
+        outstanding: Arc<Outstanding>,
+    }
+
+    impl OneFileFS {
+        fn attrs(ino: INode) -> FileAttributes {
+            if ino == ROOT_INODE {
+                FileAttributes::builder()
+                    .mode(libc::S_IFDIR | 0o755)
+                    .build()
+            } else {
+                FileAttributes::builder()
+                    .mode(libc::S_IFREG | 0o644)
+                    .build()
+            }
+        }
+    }
+
+    impl Filesystem for OneFileFS {
+        type Error = FSError;
+
+        fn getattr(&mut self, ino: INode) -> FSResult<FileAttributes> {
+            Ok(OneFileFS::attrs(ino))
+        }
+
+        fn lookup(&mut self, _parent: INode, _name: &OsStr) -> FSResult<Lookup> {
+            let inode = if self.wrong_inode {
+                INode(3)
+            } else {
+                FILE_INODE
+            };
+            self.outstanding.lookups.fetch_add(1, Ordering::Relaxed);
+
+            Ok(Lookup::builder()
+                .attributes(OneFileFS::attrs(inode))
+                .inode(inode)
+                .build())
+        }
+
+        fn forget(&mut self, _ino: INode, nlookup: u64) {
+            self.outstanding
+                .lookups
+                .fetch_sub(nlookup as i64, Ordering::Relaxed);
+        }
+
+        fn open_dir(&mut self, _ino: INode, _flags: u32) -> FSResult<OpenDir> {
+            self.outstanding.handles.fetch_add(1, Ordering::Relaxed);
+            Ok(OpenDir::builder().handle(Filehandle::from_raw(1)).build())
+        }
+
+        fn releasedir(&mut self, _ino: INode, _fh: Filehandle, _flags: u32) -> FSResult<()> {
+            self.outstanding.handles.fetch_sub(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn readdir(&mut self, dir: INode, _offset: u64) -> FSResult<Vec<DirEntry>> {
+            let entry = |name: &str, inode, typ, offset| {
+                DirEntry::builder()
+                    .name(name.into())
+                    .inode(inode)
+                    .typ(typ)
+                    .offset(offset)
+                    .build()
+            };
+
+            if self.missing_dots {
+                return Ok(vec![entry("file", FILE_INODE, FileType::Regular, 1)]);
+            }
+
+            Ok(vec![
+                entry(".", dir, FileType::Directory, 1),
+                entry("..", dir, FileType::Directory, 2),
+                entry("file", FILE_INODE, FileType::Regular, 3),
+            ])
+        }
+
+        fn open(&mut self, _ino: INode, _flags: u32) -> FSResult<OpenFile> {
+            self.outstanding.handles.fetch_add(1, Ordering::Relaxed);
+            Ok(OpenFile::builder().handle(Filehandle::from_raw(2)).build())
+        }
+
+        fn release(
+            &mut self,
+            _ino: INode,
+            _fh: Filehandle,
+            _flags: u32,
+            _flush: bool,
+        ) -> FSResult<()> {
+            self.outstanding.handles.fetch_sub(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn read(&mut self, _ino: INode, _offset: u64, _size: u32) -> FSResult<&[u8]> {
+            let len = if self.long_read {
+                DRY_RUN_READ_SIZE as usize + 1
+            } else {
+                4
+            };
+            self.data.resize(len, b'x');
+
+            Ok(&self.data)
+        }
+    }
+
+    /// Dry runs `fs`, returning the reason for every invalid reply along with what it left
+    /// outstanding
+    fn invalid_replies(fs: OneFileFS) -> (Vec<&'static str>, Arc<Outstanding>) {
+        let outstanding = Arc::clone(&fs.outstanding);
+        let report = Runner::dry_run(fs, "/nonexistent");
+
+        let reasons = report
+            .failures()
+            .map(|op| match op.outcome {
+                DryRunOutcome::Invalid(reason) => reason,
+                ref outcome => panic!("unexpected failure: {:?}", outcome),
+            })
+            .collect();
+
+        (reasons, outstanding)
+    }
+
+    #[test]
+    fn dry_run_passes_a_valid_filesystem() {
+        let (reasons, outstanding) = invalid_replies(OneFileFS::default());
+
+        assert!(reasons.is_empty(), "{:?}", reasons);
+        assert_eq!(outstanding.handles.load(Ordering::Relaxed), 0);
+        assert_eq!(outstanding.lookups.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn dry_run_rejects_long_reads() {
+        let fs = OneFileFS {
+            long_read: true,
+            ..OneFileFS::default()
+        };
+
+        let (reasons, _) = invalid_replies(fs);
+        assert_eq!(reasons, ["read returned more data than requested"]);
+    }
+
+    #[test]
+    fn dry_run_rejects_lookups_disagreeing_with_readdir() {
+        let fs = OneFileFS {
+            wrong_inode: true,
+            ..OneFileFS::default()
+        };
+
+        let (reasons, outstanding) = invalid_replies(fs);
+        assert_eq!(reasons, ["lookup disagrees with readdir on the inode"]);
+        assert_eq!(outstanding.lookups.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn dry_run_rejects_missing_dots() {
+        let fs = OneFileFS {
+            missing_dots: true,
+            ..OneFileFS::default()
+        };
+
+        let (reasons, _) = invalid_replies(fs);
+        assert_eq!(
+            reasons,
+            ["readdir must return at least the \".\" and \"..\" entries"]
+        );
+    }
+
+    struct PanickyFS;
+
+    impl Filesystem for PanickyFS {
+        type Error = FSError;
+
+        fn getattr(&mut self, _ino: INode) -> FSResult<FileAttributes> {
+            Ok(FileAttributes::builder().mode(libc::S_IFDIR).build())
+        }
+
+        fn readdir(&mut self, _dir: INode, _offset: u64) -> FSResult<Vec<crate::DirEntry>> {
+            panic!("readdir is broken")
+        }
+    }
+
+    #[test]
+    fn dry_run_catches_panics() {
+        let report = Runner::dry_run(PanickyFS, "/nonexistent");
+
+        let failures: Vec<_> = report.failures().map(|op| op.op).collect();
+        assert_eq!(failures, [OpKind::Readdir]);
+        assert!(!report.is_ok());
+    }
+}
@@ -15,6 +15,43 @@ pub enum PolyfuseError {
     ReplyError(std::io::Error),
 }
 
+/// Uses the raw OS error if there is one, otherwise the error's kind, so `?` can be used on the
+/// results of `std::fs` and friends.
+impl From<std::io::Error> for FSError {
+    fn from(e: std::io::Error) -> Self {
+        match e.raw_os_error() {
+            Some(errno) => FSError::from_errno(errno),
+            None => e.kind().into(),
+        }
+    }
+}
+
+/// Kinds without a matching variant become EIO
+impl From<std::io::ErrorKind> for FSError {
+    fn from(kind: std::io::ErrorKind) -> Self {
+        use std::io::ErrorKind;
+
+        match kind {
+            ErrorKind::NotFound => FSError::NoEntry,
+            ErrorKind::PermissionDenied => FSError::AccessDenied,
+            ErrorKind::AlreadyExists => FSError::AlreadyExists,
+            ErrorKind::InvalidInput => FSError::InvalidArgument,
+            ErrorKind::Interrupted => FSError::Interrupted,
+            ErrorKind::TimedOut => FSError::TimedOut,
+            ErrorKind::Unsupported => FSError::NotSupported,
+            ErrorKind::NotADirectory => FSError::NotDirectory,
+            ErrorKind::IsADirectory => FSError::IsDirectory,
+            ErrorKind::DirectoryNotEmpty => FSError::NotEmpty,
+            ErrorKind::ReadOnlyFilesystem => FSError::ReadOnly,
+            ErrorKind::StorageFull => FSError::NoSpace,
+            ErrorKind::CrossesDevices => FSError::CrossDevice,
+            ErrorKind::TooManyLinks => FSError::TooManyLinks,
+            ErrorKind::InvalidFilename => FSError::NameTooLong,
+            _ => FSError::Custom(libc::EIO),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -22,39 +59,184 @@ pub enum Error {
 
     #[error(transparent)]
     IoError(#[from] std::io::Error),
+
+    #[error("Filesystem::init failed")]
+    InitError(#[source] FSError),
+}
+
+/// The reason a name was rejected by `DirEntry::new`
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InvalidDirEntry {
+    #[error("Directory entry names cannot be empty")]
+    EmptyName,
+
+    #[error("Directory entry names cannot contain '/'")]
+    ContainsSlash,
+
+    #[error("Directory entry names cannot contain a nul byte")]
+    ContainsNul,
 }
 
 /// This type represents an error that occured in the filesystem struct itself.
+///
+/// Being a plain `'static` error, it converts into `Box<dyn std::error::Error>` with `?`:
+///
+/// ```
+/// # use simply_fuse::error::FSError;
+/// fn fails() -> Result<(), Box<dyn std::error::Error>> {
+///     Err(FSError::NoEntry)?
+/// }
+///
+/// assert_eq!(fails().unwrap_err().to_string(), FSError::NoEntry.to_string());
+/// ```
 #[derive(Error, Debug)]
 pub enum FSError {
     #[error("No such file or directory exists")]
     NoEntry,
 
-    #[error("Not a file")]
+    /// An operation only meant for regular files was used on something else. Directories
+    /// should use `IsDirectory` instead.
+    #[error("Is not a regular file")]
     NotFile,
 
+    #[error("Is a directory")]
+    IsDirectory,
+
     #[error("Not a directory")]
     NotDirectory,
 
     #[error("Function not implemented")]
     NotImplemented,
 
-    #[error("Invalid flags passed")]
+    #[error("Invalid flags: {0:#010x}")]
     InvalidFlags(u32),
 
-    #[error("Buffer would overflow")]
+    #[error("Result is larger than the provided buffer")]
     BufferWouldOverflow,
+
+    #[error("Interrupted system call")]
+    Interrupted,
+
+    /// The operation ran past the runner's timeout
+    #[error("Operation timed out")]
+    TimedOut,
+
+    #[error("No such attribute")]
+    NoAttribute,
+
+    #[error("File or attribute already exists")]
+    AlreadyExists,
+
+    /// The permission bits don't allow the operation
+    #[error("Permission denied")]
+    AccessDenied,
+
+    #[error("Directory not empty")]
+    NotEmpty,
+
+    #[error("Invalid argument")]
+    InvalidArgument,
+
+    /// Renaming or linking across filesystems
+    #[error("Invalid cross-device link")]
+    CrossDevice,
+
+    #[error("Bad file descriptor")]
+    BadFileDescriptor,
+
+    #[error("Operation not supported")]
+    NotSupported,
+
+    #[error("No space left on device")]
+    NoSpace,
+
+    #[error("Read-only file system")]
+    ReadOnly,
+
+    #[error("File name too long")]
+    NameTooLong,
+
+    #[error("Too many links")]
+    TooManyLinks,
+
+    /// The operation isn't allowed regardless of permission bits, such as `chown` by a user
+    /// other than root or hard linking a directory
+    #[error("Operation not permitted")]
+    PermissionDenied,
+
+    /// Any other errno, passed to the kernel as-is. Prefer a named variant where one exists.
+    #[error("{}", std::io::Error::from_raw_os_error(*.0))]
+    Custom(i32),
 }
 
 impl FSError {
-    pub const fn to_libc_error(self) -> i32 {
+    /// Creates an error which replies to the kernel with `errno`, such as `libc::ENXIO`
+    pub const fn custom(errno: i32) -> FSError {
+        Self::Custom(errno)
+    }
+
+    /// Maps `errno` to the matching named variant, falling back to `FSError::Custom`. Useful
+    /// for passing on errors from `io::Error::raw_os_error`.
+    ///
+    /// Errnos shared by several variants map to the most general one, so EINVAL becomes
+    /// `InvalidArgument` and EBADF becomes `BadFileDescriptor`.
+    ///
+    /// ```
+    /// # use simply_fuse::error::FSError;
+    /// assert!(matches!(FSError::from_errno(libc::ENOENT), FSError::NoEntry));
+    /// assert!(matches!(FSError::from_errno(libc::ENXIO), FSError::Custom(libc::ENXIO)));
+    /// ```
+    pub const fn from_errno(errno: i32) -> FSError {
+        match errno {
+            libc::ENOENT => Self::NoEntry,
+            libc::EISDIR => Self::IsDirectory,
+            libc::ENOTDIR => Self::NotDirectory,
+            libc::ENOSYS => Self::NotImplemented,
+            libc::ERANGE => Self::BufferWouldOverflow,
+            libc::EINTR => Self::Interrupted,
+            libc::ETIMEDOUT => Self::TimedOut,
+            libc::ENODATA => Self::NoAttribute,
+            libc::EEXIST => Self::AlreadyExists,
+            libc::EACCES => Self::AccessDenied,
+            libc::ENOTEMPTY => Self::NotEmpty,
+            libc::EINVAL => Self::InvalidArgument,
+            libc::EXDEV => Self::CrossDevice,
+            libc::EBADF => Self::BadFileDescriptor,
+            libc::EOPNOTSUPP => Self::NotSupported,
+            libc::EPERM => Self::PermissionDenied,
+            libc::ENOSPC => Self::NoSpace,
+            libc::EROFS => Self::ReadOnly,
+            libc::ENAMETOOLONG => Self::NameTooLong,
+            libc::EMLINK => Self::TooManyLinks,
+            errno => Self::Custom(errno),
+        }
+    }
+
+    pub const fn to_libc_error(&self) -> i32 {
         match self {
             Self::NoEntry => libc::ENOENT,
-            Self::NotFile => libc::EINVAL, // TODO is this the proper error to return?
+            Self::NotFile => libc::EBADF,
+            Self::IsDirectory => libc::EISDIR,
             Self::NotDirectory => libc::ENOTDIR,
             Self::NotImplemented => libc::ENOSYS,
             Self::InvalidFlags(_) => libc::EINVAL,
             Self::BufferWouldOverflow => libc::ERANGE,
+            Self::Interrupted => libc::EINTR,
+            Self::TimedOut => libc::ETIMEDOUT,
+            Self::NoAttribute => libc::ENODATA,
+            Self::AlreadyExists => libc::EEXIST,
+            Self::AccessDenied => libc::EACCES,
+            Self::NotEmpty => libc::ENOTEMPTY,
+            Self::InvalidArgument => libc::EINVAL,
+            Self::CrossDevice => libc::EXDEV,
+            Self::BadFileDescriptor => libc::EBADF,
+            Self::NotSupported => libc::EOPNOTSUPP,
+            Self::PermissionDenied => libc::EPERM,
+            Self::NoSpace => libc::ENOSPC,
+            Self::ReadOnly => libc::EROFS,
+            Self::NameTooLong => libc::ENAMETOOLONG,
+            Self::TooManyLinks => libc::EMLINK,
+            Self::Custom(errno) => *errno,
         }
     }
 }
@@ -0,0 +1,41 @@
+//! Helpers for filesystems exported over NFS.
+//!
+//! NFS identifies files by opaque handles which must stay valid across lookups, so they're built
+//! from an inode and its generation number. A generation should change whenever an inode number
+//! is reused, so stale handles to the old file are rejected.
+
+use crate::INode;
+
+use std::convert::TryInto;
+
+/// The length of an encoded file handle
+pub const FH_LEN: usize = 16;
+
+/// Encodes `ino` and `gen` into a 128-bit file handle
+pub fn encode_fh(ino: INode, gen: u64) -> [u8; FH_LEN] {
+    let mut fh = [0; FH_LEN];
+    fh[..8].copy_from_slice(&ino.to_u64().to_le_bytes());
+    fh[8..].copy_from_slice(&gen.to_le_bytes());
+
+    fh
+}
+
+/// Decodes a file handle created by `encode_fh`, returning `None` if it has the wrong length
+///
+/// ```
+/// # use simply_fuse::basic::ROOT_INODE;
+/// # use simply_fuse::export::{decode_fh, encode_fh};
+/// let fh = encode_fh(ROOT_INODE, 7);
+/// assert_eq!(decode_fh(&fh), Some((ROOT_INODE, 7)));
+/// assert_eq!(decode_fh(&fh[..8]), None);
+/// ```
+pub fn decode_fh(fh: &[u8]) -> Option<(INode, u64)> {
+    if fh.len() != FH_LEN {
+        return None;
+    }
+
+    let ino = u64::from_le_bytes(fh[..8].try_into().ok()?);
+    let gen = u64::from_le_bytes(fh[8..].try_into().ok()?);
+
+    Some((ino.into(), gen))
+}
@@ -0,0 +1,106 @@
+use crate::basic::INodeEntry;
+use crate::INode;
+
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::Write;
+
+use tracing::warn;
+
+/// Records changes to an `INodeTable` before they are applied, so a table can be rebuilt after a
+/// crash. Set one with `INodeTable::with_journal`.
+///
+/// Journals must be `Send` so tables using them can still be handed to `Runner::run`.
+pub trait Journal<F>: Debug {
+    /// Called before `entry` is inserted into its parent under `name`
+    fn log_insert(&mut self, ino: INode, name: &OsStr, entry: &INodeEntry<F>);
+
+    /// Called before `ino` is removed from the table
+    fn log_remove(&mut self, ino: INode);
+
+    /// Called before `name` in `parent` is moved to `new_name` in `new_parent`
+    fn log_rename(&mut self, parent: INode, name: &OsStr, new_parent: INode, new_name: &OsStr);
+
+    /// Called before `ino` is given another name, `new_name` in `new_parent`
+    fn log_link(&mut self, ino: INode, new_parent: INode, new_name: &OsStr);
+
+    /// Called before `name` is removed from `parent` while its inode keeps other names. Removing
+    /// an inode's last name is logged with `log_remove` instead.
+    fn log_unlink(&mut self, parent: INode, name: &OsStr);
+}
+
+/// A journal which discards everything. This is what tables use by default.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct NullJournal;
+
+impl<F> Journal<F> for NullJournal {
+    fn log_insert(&mut self, _ino: INode, _name: &OsStr, _entry: &INodeEntry<F>) {}
+
+    fn log_remove(&mut self, _ino: INode) {}
+
+    fn log_rename(&mut self, _parent: INode, _name: &OsStr, _new_parent: INode, _new_name: &OsStr) {
+    }
+
+    fn log_link(&mut self, _ino: INode, _new_parent: INode, _new_name: &OsStr) {}
+
+    fn log_unlink(&mut self, _parent: INode, _name: &OsStr) {}
+}
+
+/// A journal which appends one line per change to a file. Failed writes are logged and otherwise
+/// ignored, since the change itself can still be applied.
+#[derive(Debug)]
+pub struct FileJournal {
+    file: File,
+}
+
+impl FileJournal {
+    pub fn new(file: File) -> FileJournal {
+        FileJournal { file }
+    }
+
+    fn write_line(&mut self, line: std::fmt::Arguments<'_>) {
+        if let Err(e) = writeln!(self.file, "{}", line) {
+            warn!("failed to write to journal: {}", e);
+        }
+    }
+}
+
+impl<F> Journal<F> for FileJournal {
+    fn log_insert(&mut self, ino: INode, name: &OsStr, entry: &INodeEntry<F>) {
+        self.write_line(format_args!(
+            "insert {} {:?} {:?} {}",
+            ino.to_u64(),
+            entry.parent().map(INode::to_u64),
+            name,
+            entry.kind().name()
+        ));
+    }
+
+    fn log_remove(&mut self, ino: INode) {
+        self.write_line(format_args!("remove {}", ino.to_u64()));
+    }
+
+    fn log_rename(&mut self, parent: INode, name: &OsStr, new_parent: INode, new_name: &OsStr) {
+        self.write_line(format_args!(
+            "rename {} {:?} {} {:?}",
+            parent.to_u64(),
+            name,
+            new_parent.to_u64(),
+            new_name
+        ));
+    }
+
+    fn log_link(&mut self, ino: INode, new_parent: INode, new_name: &OsStr) {
+        self.write_line(format_args!(
+            "link {} {} {:?}",
+            ino.to_u64(),
+            new_parent.to_u64(),
+            new_name
+        ));
+    }
+
+    fn log_unlink(&mut self, parent: INode, name: &OsStr) {
+        self.write_line(format_args!("unlink {} {:?}", parent.to_u64(), name));
+    }
+}
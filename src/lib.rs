@@ -1,15 +1,29 @@
+#[cfg(feature = "tokio")]
+pub mod async_compat;
+#[cfg(feature = "tokio")]
+pub mod async_runner;
 pub mod attrs;
 pub mod basic;
+mod dry_run;
 pub mod error;
+pub mod export;
+pub mod journal;
+mod metrics;
 mod runner;
 
-pub use crate::runner::Runner;
+pub use crate::dry_run::{DryRunOp, DryRunOutcome, DryRunReport};
+pub use crate::metrics::{OpMetrics, RunnerMetrics};
+pub use crate::runner::{
+    Runner, RunnerBuilder, RunnerConfig, RunnerConfigBuilder, RunnerHandle, TimeoutPolicy,
+};
 
 use crate::attrs::*;
-use crate::error::{FSError, FSResult};
+use crate::error::{FSError, FSResult, InvalidDirEntry};
 
 use std::ffi::{OsStr, OsString};
 use std::io::BufRead;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
 
 use typed_builder::TypedBuilder;
@@ -59,6 +73,14 @@ impl Filehandle {
     pub const fn to_raw(self) -> u64 {
         self.0
     }
+
+    /// Returns a handle which hasn't been returned by this function before. Handles start at 1,
+    /// so they never collide with the 0 used by the default `open_dir`.
+    pub fn generate() -> Self {
+        static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+        Self(NEXT_HANDLE.fetch_add(1, Ordering::Relaxed))
+    }
 }
 
 #[derive(Debug, TypedBuilder)]
@@ -75,6 +97,97 @@ pub struct OpenFile {
     seekable: bool,
 }
 
+/// The result of creating and opening a file in a single operation
+#[derive(Debug, TypedBuilder)]
+pub struct CreateReply {
+    lookup: Lookup,
+    open: OpenFile,
+}
+
+impl From<(Lookup, OpenFile)> for CreateReply {
+    fn from((lookup, open): (Lookup, OpenFile)) -> Self {
+        CreateReply { lookup, open }
+    }
+}
+
+/// A directory entry along with what `lookup` would return for it, as returned by
+/// `Filesystem::readdirplus`
+#[derive(Debug, TypedBuilder)]
+pub struct DirEntryPlus {
+    entry: DirEntry,
+    lookup: Lookup,
+}
+
+impl From<(DirEntry, Lookup)> for DirEntryPlus {
+    fn from((entry, lookup): (DirEntry, Lookup)) -> Self {
+        DirEntryPlus { entry, lookup }
+    }
+}
+
+/// The result of a write
+#[derive(Debug, Copy, Clone, TypedBuilder)]
+pub struct WriteResult {
+    bytes_written: u32,
+
+    /// Asks the kernel to drop any data it has cached for the file once the write has been
+    /// replied to. Useful when the write changes more than the written range, or the backing
+    /// storage can be changed by someone else.
+    #[builder(default = false)]
+    invalidate_cache: bool,
+}
+
+impl WriteResult {
+    pub fn bytes_written(&self) -> u32 {
+        self.bytes_written
+    }
+
+    pub fn invalidate_cache(&self) -> bool {
+        self.invalidate_cache
+    }
+}
+
+impl From<u32> for WriteResult {
+    fn from(bytes_written: u32) -> Self {
+        WriteResult::builder().bytes_written(bytes_written).build()
+    }
+}
+
+/// Filesystem statistics, as reported by `statfs(2)` and tools like `df`. Everything defaults to
+/// 0, apart from a 4096 byte block size and a maximum name length of 255. The fragment size
+/// defaults to the block size, which is what nearly every filesystem uses.
+#[derive(Debug, Copy, Clone, TypedBuilder)]
+pub struct StatfsReply {
+    #[builder(default = 0)]
+    blocks: u64,
+
+    #[builder(default = 0)]
+    bfree: u64,
+
+    #[builder(default = 0)]
+    bavail: u64,
+
+    #[builder(default = 0)]
+    files: u64,
+
+    #[builder(default = 0)]
+    ffree: u64,
+
+    #[builder(default = 4096)]
+    bsize: u32,
+
+    #[builder(default = 255)]
+    namelen: u32,
+
+    #[builder(default = bsize)]
+    frsize: u32,
+}
+
+impl Default for StatfsReply {
+    fn default() -> Self {
+        StatfsReply::builder().build()
+    }
+}
+
 #[derive(Debug, TypedBuilder)]
 pub struct OpenDir {
     handle: Filehandle,
@@ -117,6 +230,35 @@ impl FileType {
             Self::Link => libc::DT_LNK,
         }
     }
+
+    /// Returns the type described by the `S_IF*` bits of `mode`, or `Unknown` if they don't name
+    /// one. Useful for telling what `make_node` is being asked to create.
+    pub const fn from_mode(mode: u32) -> FileType {
+        match mode & libc::S_IFMT {
+            libc::S_IFIFO => Self::FIFO,
+            libc::S_IFREG => Self::Regular,
+            libc::S_IFDIR => Self::Directory,
+            libc::S_IFSOCK => Self::Socket,
+            libc::S_IFCHR => Self::Char,
+            libc::S_IFBLK => Self::Block,
+            libc::S_IFLNK => Self::Link,
+            _ => Self::Unknown,
+        }
+    }
+
+    /// Returns the `S_IF*` bits for this type, as used in a file's mode. `Unknown` has none.
+    pub const fn to_mode_bits(self) -> u32 {
+        match self {
+            Self::FIFO => libc::S_IFIFO,
+            Self::Unknown => 0,
+            Self::Regular => libc::S_IFREG,
+            Self::Directory => libc::S_IFDIR,
+            Self::Socket => libc::S_IFSOCK,
+            Self::Char => libc::S_IFCHR,
+            Self::Block => libc::S_IFBLK,
+            Self::Link => libc::S_IFLNK,
+        }
+    }
 }
 
 #[derive(Debug, TypedBuilder, Clone)]
@@ -127,8 +269,82 @@ pub struct DirEntry {
     offset: u64,
 }
 
+impl DirEntry {
+    /// Creates a new `DirEntry`, checking that `name` is usable as a directory entry name.
+    /// Unlike `DirEntry::builder()`, this rejects names which are empty or contain a `/` or
+    /// nul byte.
+    ///
+    /// ```
+    /// # use simply_fuse::{DirEntry, FileType};
+    /// # use simply_fuse::basic::ROOT_INODE;
+    /// # use simply_fuse::error::InvalidDirEntry;
+    /// assert!(DirEntry::new("file".into(), ROOT_INODE, FileType::Regular, 3).is_ok());
+    ///
+    /// assert_eq!(
+    ///     DirEntry::new("a/b".into(), ROOT_INODE, FileType::Regular, 3).unwrap_err(),
+    ///     InvalidDirEntry::ContainsSlash
+    /// );
+    /// ```
+    pub fn new(
+        name: OsString,
+        inode: INode,
+        typ: FileType,
+        offset: u64,
+    ) -> Result<DirEntry, InvalidDirEntry> {
+        let bytes = name.as_bytes();
+
+        if bytes.is_empty() {
+            return Err(InvalidDirEntry::EmptyName);
+        } else if bytes.contains(&b'/') {
+            return Err(InvalidDirEntry::ContainsSlash);
+        } else if bytes.contains(&b'\0') {
+            return Err(InvalidDirEntry::ContainsNul);
+        }
+
+        Ok(DirEntry {
+            name,
+            inode,
+            typ,
+            offset,
+        })
+    }
+}
+
+/// How a rename should treat an existing destination
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RenameFlags {
+    /// Replace the destination if it exists. This is a plain `rename(2)`.
+    Replace,
+    /// Fail with `FSError::AlreadyExists` if the destination exists (`RENAME_NOREPLACE`)
+    NoReplace,
+    /// Atomically swap the source and destination, which must both exist (`RENAME_EXCHANGE`)
+    Exchange,
+}
+
+impl RenameFlags {
+    pub const fn to_libc_type(self) -> u32 {
+        match self {
+            Self::Replace => 0,
+            Self::NoReplace => libc::RENAME_NOREPLACE,
+            Self::Exchange => libc::RENAME_EXCHANGE,
+        }
+    }
+
+    /// Returns `None` for unknown flags, and for flags that can't be combined
+    pub const fn from_libc_type(from: u32) -> Option<Self> {
+        match from {
+            0 => Some(Self::Replace),
+            libc::RENAME_NOREPLACE => Some(Self::NoReplace),
+            libc::RENAME_EXCHANGE => Some(Self::Exchange),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SetXAttrFlags {
+    /// Sets the attribute whether or not it exists, as when no flags are given
+    CreateOrReplace,
     Create,
     Replace,
 }
@@ -136,6 +352,7 @@ pub enum SetXAttrFlags {
 impl SetXAttrFlags {
     pub const fn to_libc_type(self) -> i32 {
         match self {
+            Self::CreateOrReplace => 0,
             Self::Create => libc::XATTR_CREATE,
             Self::Replace => libc::XATTR_REPLACE,
         }
@@ -146,19 +363,222 @@ impl SetXAttrFlags {
         let replace = from & libc::XATTR_REPLACE != 0;
 
         if create && replace {
-            // (false && false) and (true && true)
             None
         } else if create {
             Some(Self::Create)
         } else if replace {
             Some(Self::Replace)
         } else {
-            // I don't think this case would ever actually be called
+            // the usual `setxattr(2)` call, and what `setfattr` sends
+            Some(Self::CreateOrReplace)
+        }
+    }
+}
+
+/// The checks requested by `access(2)`. An empty mask is `F_OK`, which only asks whether the
+/// file exists.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AccessMask(u32);
+
+impl AccessMask {
+    pub const EXISTS: AccessMask = AccessMask(libc::F_OK as u32);
+    pub const READ: AccessMask = AccessMask(libc::R_OK as u32);
+    pub const WRITE: AccessMask = AccessMask(libc::W_OK as u32);
+    pub const EXECUTE: AccessMask = AccessMask(libc::X_OK as u32);
+
+    /// Keeps only the `R_OK`, `W_OK` and `X_OK` bits of `mask`
+    pub const fn from_libc_type(mask: u32) -> AccessMask {
+        AccessMask(mask & (libc::R_OK | libc::W_OK | libc::X_OK) as u32)
+    }
+
+    pub const fn to_libc_type(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: AccessMask) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether this only checks for existence
+    pub const fn is_exists(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for AccessMask {
+    type Output = AccessMask;
+
+    fn bitor(self, rhs: AccessMask) -> AccessMask {
+        AccessMask(self.0 | rhs.0)
+    }
+}
+
+/// How `fallocate(2)` should change a file. An empty mode allocates the range, growing the file
+/// if it ends past the current size.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FallocateMode(u32);
+
+impl FallocateMode {
+    /// Allocate without changing the file size
+    pub const KEEP_SIZE: FallocateMode = FallocateMode(libc::FALLOC_FL_KEEP_SIZE as u32);
+    /// Deallocate the range, so it reads back as zeroes. Always comes with `KEEP_SIZE`.
+    pub const PUNCH_HOLE: FallocateMode = FallocateMode(libc::FALLOC_FL_PUNCH_HOLE as u32);
+    /// Zero the range, allocating it if needed
+    pub const ZERO_RANGE: FallocateMode = FallocateMode(libc::FALLOC_FL_ZERO_RANGE as u32);
+    /// Remove the range, shifting everything after it down
+    pub const COLLAPSE_RANGE: FallocateMode = FallocateMode(libc::FALLOC_FL_COLLAPSE_RANGE as u32);
+
+    const ALL: u32 =
+        Self::KEEP_SIZE.0 | Self::PUNCH_HOLE.0 | Self::ZERO_RANGE.0 | Self::COLLAPSE_RANGE.0;
+
+    /// Returns `None` if `mode` has bits set which aren't covered by this type
+    pub const fn from_libc_type(mode: u32) -> Option<FallocateMode> {
+        if mode & !Self::ALL == 0 {
+            Some(FallocateMode(mode))
+        } else {
             None
         }
     }
+
+    pub const fn to_libc_type(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: FallocateMode) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl std::ops::BitOr for FallocateMode {
+    type Output = FallocateMode;
+
+    fn bitor(self, rhs: FallocateMode) -> FallocateMode {
+        FallocateMode(self.0 | rhs.0)
+    }
+}
+
+/// Optional FUSE features, negotiated with the kernel when mounting. See `Filesystem::init`.
+///
+/// The default set is what polyfuse asks for unless told otherwise.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct InitCapabilities(u32);
+
+impl InitCapabilities {
+    pub const ASYNC_READ: InitCapabilities = InitCapabilities(1);
+    pub const POSIX_LOCKS: InitCapabilities = InitCapabilities(1 << 1);
+    pub const ATOMIC_O_TRUNC: InitCapabilities = InitCapabilities(1 << 3);
+    pub const EXPORT_SUPPORT: InitCapabilities = InitCapabilities(1 << 4);
+    pub const DONT_MASK: InitCapabilities = InitCapabilities(1 << 6);
+    pub const FLOCK_LOCKS: InitCapabilities = InitCapabilities(1 << 10);
+    pub const AUTO_INVAL_DATA: InitCapabilities = InitCapabilities(1 << 12);
+    pub const READDIRPLUS: InitCapabilities = InitCapabilities(1 << 13);
+    pub const READDIRPLUS_AUTO: InitCapabilities = InitCapabilities(1 << 14);
+    pub const ASYNC_DIO: InitCapabilities = InitCapabilities(1 << 15);
+    pub const WRITEBACK_CACHE: InitCapabilities = InitCapabilities(1 << 16);
+    pub const PARALLEL_DIROPS: InitCapabilities = InitCapabilities(1 << 18);
+    pub const HANDLE_KILLPRIV: InitCapabilities = InitCapabilities(1 << 19);
+    pub const POSIX_ACL: InitCapabilities = InitCapabilities(1 << 20);
+
+    pub const fn empty() -> InitCapabilities {
+        InitCapabilities(0)
+    }
+
+    pub const fn to_libc_type(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: InitCapabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: InitCapabilities) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: InitCapabilities) {
+        self.0 &= !other.0;
+    }
+
+    pub fn set(&mut self, other: InitCapabilities, enabled: bool) {
+        if enabled {
+            self.insert(other);
+        } else {
+            self.remove(other);
+        }
+    }
+}
+
+impl Default for InitCapabilities {
+    fn default() -> Self {
+        Self::ASYNC_READ
+            | Self::PARALLEL_DIROPS
+            | Self::AUTO_INVAL_DATA
+            | Self::HANDLE_KILLPRIV
+            | Self::ASYNC_DIO
+            | Self::ATOMIC_O_TRUNC
+    }
+}
+
+impl std::ops::BitOr for InitCapabilities {
+    type Output = InitCapabilities;
+
+    fn bitor(self, rhs: InitCapabilities) -> InitCapabilities {
+        InitCapabilities(self.0 | rhs.0)
+    }
+}
+
+/// Identifies the kind of FUSE operation being processed, independent of its arguments.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum OpKind {
+    Lookup,
+    Getattr,
+    Setattr,
+    Readlink,
+    Symlink,
+    Mknod,
+    Mkdir,
+    Unlink,
+    Rmdir,
+    Rename,
+    Link,
+    Open,
+    Read,
+    Write,
+    Release,
+    Statfs,
+    Fsync,
+    Setxattr,
+    Getxattr,
+    Listxattr,
+    Removexattr,
+    Flush,
+    Opendir,
+    Readdir,
+    Releasedir,
+    Fsyncdir,
+    Getlk,
+    Setlk,
+    Flock,
+    Access,
+    Create,
+    Bmap,
+    Fallocate,
+    CopyFileRange,
+    Poll,
+    Forget,
+    Interrupt,
+    NotifyReply,
+    Unknown,
 }
 
+/// Alias for `OpKind`, which already covers every operation the runner can receive and is what
+/// `pre_op`, `post_op` and `TimeoutPolicy` identify operations by.
+pub type OperationType = OpKind;
+
 /// Acts as a reference to an xattr, containing a slice for the requested data along with the
 /// length of its data source.
 #[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
@@ -183,43 +603,334 @@ impl<'a> XAttrRef<'a> {
     }
 }
 
+/// An owned version of `XAttrRef`, for xattr values which are computed rather than stored.
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct XAttrValue {
+    full_len: usize,
+    data: Vec<u8>,
+}
+
+impl XAttrValue {
+    pub fn new(data: Vec<u8>, full_len: usize) -> XAttrValue {
+        XAttrValue { full_len, data }
+    }
+
+    pub fn full_len(&self) -> usize {
+        self.full_len
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl From<XAttrRef<'_>> for XAttrValue {
+    fn from(val: XAttrRef<'_>) -> Self {
+        XAttrValue::new(val.data.to_vec(), val.full_len)
+    }
+}
+
 pub trait Filesystem {
-    fn open(&mut self, _ino: INode, _flags: u32) -> FSResult<OpenFile> {
-        Err(FSError::NotImplemented)
+    /// The error returned by every operation. Use `FSError` unless you want to carry more
+    /// context, such as your storage layer's errors; the runner converts it into an `FSError`
+    /// to reply to the kernel. It must also convert from `FSError`, which the default
+    /// implementations return.
+    ///
+    /// There is no default, as associated type defaults aren't stable yet, so implementations
+    /// that don't need their own error add `type Error = FSError;`.
+    type Error: Into<FSError> + From<FSError>;
+
+    /// Called just before mounting with the capabilities the runner is going to ask the kernel
+    /// for. Clear the ones the filesystem can't support, or add ones it wants. The kernel may
+    /// still turn down any of the returned capabilities. Returning an error aborts the mount.
+    fn init(&mut self, capabilities: InitCapabilities) -> FSResult<InitCapabilities, Self::Error> {
+        Ok(capabilities)
+    }
+
+    /// Called once the session has ended, whether by unmounting or because of an error, right
+    /// before `Runner::run_block` returns. The place to flush buffers or persist state.
+    fn destroy(&mut self) {}
+
+    /// Called before every operation is dispatched. Returning an error rejects the operation
+    /// and replies to the kernel with that error without calling the operation itself.
+    ///
+    /// Useful for concerns that apply to every operation, such as access control or
+    /// rate-limiting.
+    fn pre_op(&mut self, _op: &OpKind) -> FSResult<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Called after every operation that made it past `pre_op`, with the result the operation
+    /// produced.
+    fn post_op(&mut self, _op: &OpKind, _result: &FSResult<()>) {}
+
+    /// The kernel has dropped `nlookup` of its references to `ino`, each of which came from a
+    /// successful `lookup`, `create`, `make_node`, `make_dir`, `make_symlink` or `link`. Once
+    /// every reference is gone, an unlinked inode can be freed. The kernel expects no reply.
+    fn forget(&mut self, _ino: INode, _nlookup: u64) {}
+
+    /// Several `forget`s at once, which `forget` is called for one at a time by default
+    fn batch_forget(&mut self, nodes: &[(INode, u64)]) {
+        for &(ino, nlookup) in nodes {
+            self.forget(ino, nlookup);
+        }
+    }
+
+    fn open(&mut self, _ino: INode, _flags: u32) -> FSResult<OpenFile, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Called on every `close(2)` of a descriptor using `fh`, before `release`. `lock_owner`
+    /// identifies whoever is closing it. Returns `FSError::NotImplemented` by default, which
+    /// tells the kernel not to send flushes for this filesystem again.
+    fn flush(
+        &mut self,
+        _ino: INode,
+        _fh: Filehandle,
+        _lock_owner: u64,
+    ) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    fn open_dir(&mut self, _ino: INode, _flags: u32) -> FSResult<OpenDir> {
-        Err(FSError::NotImplemented)
+    /// Copies up to `len` bytes from one open file to another without the data passing through
+    /// userspace, returning how many bytes were copied. Both files may be the same. Returning
+    /// `FSError::NotImplemented` makes the kernel fall back to reading and writing.
+    #[allow(clippy::too_many_arguments)]
+    fn copy_file_range(
+        &mut self,
+        _src_ino: INode,
+        _src_fh: Filehandle,
+        _src_offset: u64,
+        _dst_ino: INode,
+        _dst_fh: Filehandle,
+        _dst_offset: u64,
+        _len: u64,
+        _flags: u64,
+    ) -> FSResult<u64, Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
+    /// Allocates, deallocates or zeroes `length` bytes at `offset`, as described by `mode`.
+    /// Modes the filesystem can't handle should fail with `FSError::NotSupported`.
+    fn fallocate(
+        &mut self,
+        _ino: INode,
+        _fh: Filehandle,
+        _offset: u64,
+        _length: u64,
+        _mode: FallocateMode,
+    ) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Makes everything written through `fh` durable. If `datasync` is set, as for
+    /// `fdatasync(2)`, metadata other than what's needed to read the data back can be skipped.
+    /// Returns `FSError::NotImplemented` by default, which the kernel treats as success from
+    /// then on.
+    fn fsync(
+        &mut self,
+        _ino: INode,
+        _fh: Filehandle,
+        _datasync: bool,
+    ) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Called once the last descriptor sharing `fh` is closed. `flush` is set if the kernel
+    /// wants buffered data written out as well. Nothing is done by default.
+    fn release(
+        &mut self,
+        _ino: INode,
+        _fh: Filehandle,
+        _flags: u32,
+        _flush: bool,
+    ) -> FSResult<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Succeeds with a handle of 0 by default, so that filesystems which only implement
+    /// `readdir` can still be listed. Override this if you need per-handle state.
+    fn open_dir(&mut self, _ino: INode, _flags: u32) -> FSResult<OpenDir, Self::Error> {
+        Ok(OpenDir::builder().handle(Filehandle::from_raw(0)).build())
+    }
+
+    /// The directory counterpart of `fsync`
+    fn fsyncdir(
+        &mut self,
+        _ino: INode,
+        _fh: Filehandle,
+        _datasync: bool,
+    ) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// The directory counterpart of `release`. Nothing is done by default.
+    fn releasedir(
+        &mut self,
+        _ino: INode,
+        _fh: Filehandle,
+        _flags: u32,
+    ) -> FSResult<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Creates a regular file, device node, FIFO or socket, as `mknod(2)` does. The kind of
+    /// node is in the `S_IF*` bits of `mode`, see `FileType::from_mode`. `rdev` is only
+    /// meaningful for device nodes.
     fn make_node(
         &mut self,
         _parent: INode,
         _name: &OsStr,
         _mode: u32,
         _rdev: u32,
-    ) -> FSResult<Lookup> {
-        Err(FSError::NotImplemented)
+    ) -> FSResult<Lookup, Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    fn make_dir(&mut self, _parent: INode, _name: &OsStr, _mode: u32) -> FSResult<Lookup> {
-        Err(FSError::NotImplemented)
+    /// Atomically creates and opens a file, saving the kernel from calling `make_node` and then
+    /// `open`. `flags` are the flags the file is being opened with.
+    ///
+    /// Exclusive creates (`O_EXCL`) never reach this method; the runner sends them to
+    /// `create_exclusive` instead. Returning `FSError::NotImplemented` makes the kernel fall
+    /// back to `make_node` followed by `open`.
+    fn create(
+        &mut self,
+        _parent: INode,
+        _name: &OsStr,
+        _mode: u32,
+        _flags: u32,
+    ) -> FSResult<CreateReply, Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    fn make_symlink(&mut self, _parent: INode, _name: &OsStr, _link: &OsStr) -> FSResult<Lookup> {
-        Err(FSError::NotImplemented)
+    /// Atomically creates and opens a file which must not already exist, as for
+    /// `O_CREAT | O_EXCL`. If `name` already exists in `parent`, this should fail rather than
+    /// opening the existing file. The runner calls this instead of `create` whenever the open
+    /// flags include `O_EXCL`.
+    fn create_exclusive(
+        &mut self,
+        _parent: INode,
+        _name: &OsStr,
+        _mode: u32,
+    ) -> FSResult<CreateReply, Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    fn lookup(&mut self, _parent: INode, _name: &OsStr) -> FSResult<Lookup> {
-        Err(FSError::NotImplemented)
+    fn make_dir(
+        &mut self,
+        _parent: INode,
+        _name: &OsStr,
+        _mode: u32,
+    ) -> FSResult<Lookup, Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    fn getattr(&mut self, _inode: INode) -> FSResult<FileAttributes> {
-        Err(FSError::NotImplemented)
+    /// Creates a symlink named `name` in `parent`, pointing at `link`. The new entry's mode
+    /// should be `S_IFLNK | 0o777`.
+    fn make_symlink(
+        &mut self,
+        _parent: INode,
+        _name: &OsStr,
+        _link: &OsStr,
+    ) -> FSResult<Lookup, Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    fn setattr(&mut self, _inode: INode, _attr: SetFileAttributes) -> FSResult<FileAttributes> {
-        Err(FSError::NotImplemented)
+    /// Returns the target of the symlink `ino`
+    fn readlink(&mut self, _ino: INode) -> FSResult<&OsStr, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Creates `new_name` in `new_parent` as a hard link to `ino`. The returned `Lookup` should
+    /// be for `ino`, with its link count incremented.
+    fn link(
+        &mut self,
+        _ino: INode,
+        _new_parent: INode,
+        _new_name: &OsStr,
+    ) -> FSResult<Lookup, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Removes the non-directory `name` from `parent`. Should fail with `FSError::IsDirectory`
+    /// if `name` is a directory.
+    fn unlink(&mut self, _parent: INode, _name: &OsStr) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Removes the directory `name` from `parent`. Should fail with `FSError::NotEmpty` if the
+    /// directory still has children.
+    fn rmdir(&mut self, _parent: INode, _name: &OsStr) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Moves `name` in `parent` to `new_name` in `new_parent`. See `RenameFlags` for how an
+    /// existing destination should be handled. Filesystems that don't support some of the flags
+    /// should return `FSError::NotSupported` for them.
+    fn rename(
+        &mut self,
+        _parent: INode,
+        _name: &OsStr,
+        _new_parent: INode,
+        _new_name: &OsStr,
+        _flags: RenameFlags,
+    ) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    fn lookup(&mut self, _parent: INode, _name: &OsStr) -> FSResult<Lookup, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    fn getattr(&mut self, _inode: INode) -> FSResult<FileAttributes, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Returns statistics about the filesystem containing `ino`. Defaults to an empty filesystem
+    /// rather than an error, so tools like `df` work without an implementation.
+    fn statfs(&mut self, _ino: INode) -> FSResult<StatfsReply, Self::Error> {
+        Ok(StatfsReply::default())
+    }
+
+    /// Checks whether the user `uid` in group `gid` may access `ino` as described by `mask`.
+    ///
+    /// By default this checks the permission bits returned by `getattr`. The kernel only sends
+    /// this when the filesystem isn't mounted with `default_permissions`.
+    fn access(
+        &mut self,
+        ino: INode,
+        uid: u32,
+        gid: u32,
+        mask: AccessMask,
+    ) -> FSResult<(), Self::Error> {
+        Ok(self.getattr(ino)?.check_access(uid, gid, mask)?)
+    }
+
+    /// Applies `attr` to `inode` and returns the resulting attributes.
+    ///
+    /// `Runner` calls `getattr` instead when `attr` would not change anything, see
+    /// `SetFileAttributes::is_noop`.
+    fn setattr(
+        &mut self,
+        _inode: INode,
+        _attr: SetFileAttributes,
+    ) -> FSResult<FileAttributes, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Changes the size of a file, either cutting it short or extending it with zeros.
+    ///
+    /// `Runner` calls this instead of `setattr` when the size is the only attribute being
+    /// changed. The default implementation forwards to `setattr` with only `size` set.
+    fn truncate(
+        &mut self,
+        ino: INode,
+        _fh: Option<Filehandle>,
+        size: u64,
+    ) -> FSResult<FileAttributes, Self::Error> {
+        let attrs = SetFileAttributes::builder().size(Some(size)).build();
+
+        self.setattr(ino, attrs)
     }
 
     fn setxattr(
@@ -228,8 +939,8 @@ pub trait Filesystem {
         _attr_name: &OsStr,
         _attr_value: &[u8],
         _flags: SetXAttrFlags,
-    ) -> FSResult<()> {
-        Err(FSError::NotImplemented)
+    ) -> FSResult<(), Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
     /// When `max_len == 0`, this is functionally requesting only the length of the requested
@@ -239,8 +950,20 @@ pub trait Filesystem {
         _ino: INode,
         _attr_name: &OsStr,
         _max_len: u32,
-    ) -> FSResult<XAttrRef<'_>> {
-        Err(FSError::NotImplemented)
+    ) -> FSResult<XAttrRef<'_>, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Like `getxattr`, but returns an owned value so it can be computed on the fly instead of
+    /// borrowed from `self`. This is what `Runner` calls, and by default it copies the value
+    /// returned by `getxattr`, so only one of the two needs to be implemented.
+    fn getxattr_owned(
+        &mut self,
+        ino: INode,
+        attr_name: &OsStr,
+        max_len: u32,
+    ) -> FSResult<XAttrValue, Self::Error> {
+        self.getxattr(ino, attr_name, max_len).map(XAttrValue::from)
     }
 
     /// When `max_len` is 0, the return value should be an empty string and the length of all the
@@ -249,8 +972,8 @@ pub trait Filesystem {
     /// When `max_len` is greater than 0, this function should return an `OsString` composed of all
     /// the xattr names seperated by a nul (\0) byte. If the length of that string is greater than
     /// `max_len`, however, the method should error and return `FSError::BufferWouldOverflow`.
-    fn listxattrs(&mut self, _ino: INode, _max_len: u32) -> FSResult<(OsString, u32)> {
-        Err(FSError::NotImplemented)
+    fn listxattrs(&mut self, _ino: INode, _max_len: u32) -> FSResult<(OsString, u32), Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
     /// Reads a directory.
@@ -259,22 +982,125 @@ pub trait Filesystem {
     /// This method **must** include the "." and ".." directories, as well as properly accounting
     /// for `offset`. If not, some operations may get stuck in an infinite loop while trying to
     /// read a directory.
-    fn readdir(&mut self, _dir: INode, _offset: u64) -> FSResult<Vec<DirEntry>> {
-        Err(FSError::NotImplemented)
+    ///
+    /// When `offset` is 0, "." and ".." must be the first two entries at offsets 1 and 2.
+    /// `basic::check_readdir_entries` can be used to verify this, and debug builds of `Runner`
+    /// log a warning when it doesn't hold.
+    fn readdir(&mut self, _dir: INode, _offset: u64) -> FSResult<Vec<DirEntry>, Self::Error> {
+        Err(FSError::NotImplemented.into())
+    }
+
+    /// Like `readdir`, but each entry also carries its `lookup` reply, which saves the kernel
+    /// from looking every entry up separately. Only used when the `READDIRPLUS` capability is
+    /// negotiated, see `Filesystem::init`.
+    ///
+    /// By default this calls `readdir`, then `lookup` for every entry apart from "." and "..",
+    /// which use `getattr` as the kernel ignores their lookup.
+    fn readdirplus(&mut self, dir: INode, offset: u64) -> FSResult<Vec<DirEntryPlus>, Self::Error> {
+        self.readdir(dir, offset)?
+            .into_iter()
+            .map(|entry| {
+                let lookup = if entry.name == "." || entry.name == ".." {
+                    Lookup::builder()
+                        .attributes(self.getattr(entry.inode)?)
+                        .inode(entry.inode)
+                        .build()
+                } else {
+                    self.lookup(dir, &entry.name)?
+                };
+
+                Ok(DirEntryPlus { entry, lookup })
+            })
+            .collect()
     }
 
-    fn read(&mut self, _ino: INode, _offset: u64, _size: u32) -> FSResult<&[u8]> {
-        Err(FSError::NotImplemented)
+    /// Called right before every `read` with the same arguments. Useful as a prefetch hint.
+    fn pre_read(&mut self, _ino: INode, _offset: u64, _size: u32) {}
+
+    fn read(&mut self, _ino: INode, _offset: u64, _size: u32) -> FSResult<&[u8], Self::Error> {
+        Err(FSError::NotImplemented.into())
     }
 
-    /// Returns the amount of bytes written
+    /// Returns the amount of bytes written. A bare `u32` can be converted with `.into()` if the
+    /// cache doesn't need to be invalidated.
+    ///
+    /// Returning 0 bytes written is only valid when `size` is also 0. Some kernels treat a
+    /// zero-length reply to a non-empty write as an error or simply resend the write, so report a
+    /// failure with an `Err` instead.
+    ///
+    /// With `Runner::with_writeback_cache` enabled, writes are coalesced by the kernel before
+    /// they reach you. They may arrive out of order, be larger than the writes the user made,
+    /// and be sent for files which were only opened for reading.
+    ///
+    /// Defaults to `FSError::BadFileDescriptor`, as if the file wasn't opened for writing.
+    /// Returning ENOSYS here would make some kernels stop sending writes for the whole mount.
     fn write<T: BufRead>(
         &mut self,
         _ino: INode,
         _offset: u64,
         _size: u32,
         _buf: T,
-    ) -> FSResult<u32> {
-        Err(FSError::NotImplemented)
+    ) -> FSResult<WriteResult, Self::Error> {
+        Err(FSError::BadFileDescriptor.into())
+    }
+
+    /// Writes with `O_APPEND` semantics, placing the data at the current end of the file rather
+    /// than at a caller-provided offset.
+    ///
+    /// `Runner` calls this instead of `write` when the file was opened with `O_APPEND`, unless
+    /// writeback caching is enabled. The kernel then sends merged page writes at offsets it has
+    /// worked out itself, so those always go to `write`. The default implementation looks up the
+    /// current size with `getattr` and then calls `write` at that offset.
+    fn write_append<T: BufRead>(
+        &mut self,
+        ino: INode,
+        _fh: Filehandle,
+        size: u32,
+        buf: T,
+    ) -> FSResult<WriteResult, Self::Error> {
+        let offset = self.getattr(ino)?.size();
+        self.write(ino, offset, size, buf)
+    }
+
+    /// Called after every successful `write` or `write_append`, with the size that was requested
+    /// and the amount that was actually `written`. Useful for write-through caching or
+    /// replication. For appends, `offset` is the one sent by the kernel rather than the end of
+    /// the file.
+    fn post_write(&mut self, _ino: INode, _offset: u64, _size: u32, _written: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn setxattr_flags_from_libc() {
+        assert_eq!(
+            SetXAttrFlags::from_libc_type(0),
+            Some(SetXAttrFlags::CreateOrReplace)
+        );
+        assert_eq!(
+            SetXAttrFlags::from_libc_type(libc::XATTR_CREATE),
+            Some(SetXAttrFlags::Create)
+        );
+        assert_eq!(
+            SetXAttrFlags::from_libc_type(libc::XATTR_CREATE | libc::XATTR_REPLACE),
+            None
+        );
+    }
+
+    #[test]
+    fn file_type_from_mode() {
+        for typ in [
+            FileType::FIFO,
+            FileType::Char,
+            FileType::Link,
+            FileType::Socket,
+        ] {
+            let mode = typ.to_mode_bits() | 0o644;
+            assert_eq!(FileType::from_mode(mode).to_mode_bits(), typ.to_mode_bits());
+        }
+
+        assert!(matches!(FileType::from_mode(0o644), FileType::Unknown));
     }
 }
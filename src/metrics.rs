@@ -0,0 +1,128 @@
+use crate::OpKind;
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+const OP_KINDS: usize = OpKind::Unknown as usize + 1;
+
+/// Counters for a single kind of operation
+#[derive(Debug, Default)]
+pub struct OpMetrics {
+    calls: AtomicU64,
+    errors: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl OpMetrics {
+    /// How many times the operation was received
+    pub fn calls(&self) -> u64 {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    pub fn successes(&self) -> u64 {
+        self.calls().saturating_sub(self.errors())
+    }
+
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Total time spent handling the operation, including replying to the kernel
+    pub fn total_time(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
+
+/// Counts the operations a `Runner` handles, broken down by `OpKind`, along with whether they
+/// succeeded and how long they took. Set one with `Runner::with_metrics`.
+///
+/// Every counter is an atomic, so the metrics can be shared with another thread (such as an
+/// exporter) through the `Arc` while the runner is serving requests.
+///
+/// ```
+/// use simply_fuse::{OpKind, RunnerMetrics};
+///
+/// let metrics = RunnerMetrics::new();
+/// assert_eq!(metrics.total(), 0);
+/// assert_eq!(metrics.op(OpKind::Read).calls(), 0);
+/// ```
+pub struct RunnerMetrics {
+    ops: [OpMetrics; OP_KINDS],
+    successes: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl RunnerMetrics {
+    pub fn new() -> RunnerMetrics {
+        RunnerMetrics {
+            ops: std::array::from_fn(|_| OpMetrics::default()),
+            successes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the counters for `kind`
+    pub fn op(&self, kind: OpKind) -> &OpMetrics {
+        &self.ops[kind as usize]
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.op(OpKind::Read).calls()
+    }
+
+    pub fn writes(&self) -> u64 {
+        self.op(OpKind::Write).calls()
+    }
+
+    pub fn lookups(&self) -> u64 {
+        self.op(OpKind::Lookup).calls()
+    }
+
+    /// Operations received across every kind
+    pub fn total(&self) -> u64 {
+        self.successes() + self.errors()
+    }
+
+    /// Operations which succeeded across every kind
+    pub fn successes(&self) -> u64 {
+        self.successes.load(Ordering::Relaxed)
+    }
+
+    /// Operations which failed across every kind, including those rejected by `pre_op`
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, kind: OpKind, success: bool, elapsed: Duration) {
+        let op = self.op(kind);
+        op.calls.fetch_add(1, Ordering::Relaxed);
+        op.nanos.fetch_add(
+            u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+
+        if success {
+            self.successes.fetch_add(1, Ordering::Relaxed);
+        } else {
+            op.errors.fetch_add(1, Ordering::Relaxed);
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Default for RunnerMetrics {
+    fn default() -> RunnerMetrics {
+        RunnerMetrics::new()
+    }
+}
+
+impl fmt::Debug for RunnerMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the per-op counters are left out, since most kinds are never seen
+        f.debug_struct("RunnerMetrics")
+            .field("successes", &self.successes())
+            .field("errors", &self.errors())
+            .finish_non_exhaustive()
+    }
+}
@@ -1,13 +1,30 @@
 use crate::attrs::{FileAttributes, SetFileAttributes};
-use crate::error::{FSError, PolyfuseError, Result};
-use crate::{Filesystem, INode, Lookup, SetXAttrFlags};
-
+use crate::basic::check_readdir_entries;
+use crate::error::{Error, FSError, FSResult, PolyfuseError, Result};
+use crate::{
+    AccessMask, DirEntryPlus, FallocateMode, Filehandle, Filesystem, INode, InitCapabilities,
+    Lookup, OpKind, OpenDir, OpenFile, RenameFlags, RunnerMetrics, SetXAttrFlags, StatfsReply,
+};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
 use std::io::BufRead;
+use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
-use std::thread::JoinHandle;
-
-use polyfuse::{op, reply, KernelConfig, Operation, Request, Session};
-use tracing::{error, warn};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use polyfuse::bytes::{self, FillBytes};
+use polyfuse::{op, reply, KernelConfig, Notifier, Operation, Request, Session};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{error, info, info_span, warn};
+use typed_builder::TypedBuilder;
 
 impl Lookup {
     fn apply_attrs_to(&self, attrs: &mut reply::FileAttr) {
@@ -17,7 +34,7 @@ impl Lookup {
 
 impl FileAttributes {
     /// Copies the attributes from our own `FileAttributes` to a polyfuse `FileAttr`.
-    fn copy_attrs_to(&self, ino: INode, attrs: &mut reply::FileAttr) {
+    pub(crate) fn copy_attrs_to(&self, ino: INode, attrs: &mut reply::FileAttr) {
         attrs.ino(ino.to_u64());
 
         attrs.size(self.size());
@@ -58,141 +75,1181 @@ impl From<Lookup> for reply::EntryOut {
     }
 }
 
-#[derive(Debug)]
+impl From<OpenFile> for reply::OpenOut {
+    fn from(val: OpenFile) -> Self {
+        let mut out = reply::OpenOut::default();
+
+        out.fh(val.handle.to_raw());
+        out.direct_io(val.direct_io);
+        out.keep_cache(val.keep_cache);
+        out.nonseekable(!val.seekable);
+        out.cache_dir(false); // I think this only works for readdir
+
+        out
+    }
+}
+
+impl From<OpenDir> for reply::OpenOut {
+    fn from(val: OpenDir) -> Self {
+        let mut out = reply::OpenOut::default();
+
+        out.fh(val.handle.to_raw());
+        out.direct_io(val.direct_io);
+        out.keep_cache(val.keep_cache);
+        out.nonseekable(!val.seekable);
+        out.cache_dir(val.cache_dir);
+
+        out
+    }
+}
+
+impl From<StatfsReply> for reply::StatfsOut {
+    fn from(val: StatfsReply) -> Self {
+        let mut out = reply::StatfsOut::default();
+        let st = out.statfs();
+
+        st.blocks(val.blocks);
+        st.bfree(val.bfree);
+        st.bavail(val.bavail);
+        st.files(val.files);
+        st.ffree(val.ffree);
+        st.bsize(val.bsize);
+        st.namelen(val.namelen);
+        st.frsize(val.frsize);
+
+        out
+    }
+}
+
+/// How often `wait_for_shutdown` checks whether the session has already ended
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Unmounts `mountpoint` once something is sent on `shutdown`. The kernel then ends the session,
+/// which makes `Runner::serve` return. Gives up once `done` is set or the sender is dropped.
+fn wait_for_shutdown(shutdown: Receiver<()>, done: &AtomicBool, mountpoint: &Path) {
+    loop {
+        match shutdown.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+            Ok(()) => break,
+            Err(RecvTimeoutError::Timeout) if !done.load(Ordering::Relaxed) => continue,
+            Err(_) => return,
+        }
+    }
+
+    // the same lazy unmount polyfuse does when a session is dropped
+    let status = Command::new("fusermount")
+        .args(["-u", "-q", "-z", "--"])
+        .arg(mountpoint)
+        .status();
+
+    match status {
+        Ok(status) if status.success() => (),
+        Ok(status) => error!(
+            "fusermount failed to unmount {}: {}",
+            mountpoint.display(),
+            status
+        ),
+        Err(e) => error!("failed to run fusermount: {}", e),
+    }
+}
+
+/// What `Runner::call` tells the timeout watchdog
+enum Watch {
+    Started {
+        op: OpKind,
+        timeout: Duration,
+        /// The handler's span, so the warning carries the inode
+        span: tracing::Span,
+    },
+    Finished,
+}
+
+/// Warns about calls still running past their timeout, while they're running. One of these runs
+/// per session when a `TimeoutPolicy` is set, and stops once the sender is dropped.
+fn watch_timeouts(events: Receiver<Watch>) {
+    while let Ok(event) = events.recv() {
+        if let Watch::Started { op, timeout, span } = event {
+            match events.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let _span = span.entered();
+                    warn!(
+                        "{:?} is still running after its timeout of {:?}",
+                        op, timeout
+                    );
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+                Ok(_) => (),
+            }
+        }
+    }
+}
+
+/// Converts a setattr request into the attributes it changes
+pub(crate) fn set_file_attributes(op: &op::Setattr<'_>) -> SetFileAttributes {
+    let to_duration = |spec: op::SetAttrTime| {
+        use op::SetAttrTime;
+
+        match spec {
+            SetAttrTime::Timespec(dur) => Some(dur),
+            SetAttrTime::Now => Some(std::time::UNIX_EPOCH.elapsed().unwrap()),
+            spec => {
+                error!(
+                    "Unknown timespec \"{:#?}\" encountered. Assuming `None` for now!",
+                    spec
+                );
+
+                None
+            }
+        }
+    };
+
+    SetFileAttributes::builder()
+        .mode(op.mode())
+        .size(op.size())
+        .uid(op.uid())
+        .gid(op.gid())
+        .atime(op.atime().and_then(to_duration))
+        .mtime(op.mtime().and_then(to_duration))
+        .ctime(op.ctime())
+        .build()
+}
+
+/// The reply to a readdirplus request. polyfuse only knows how to encode plain readdir replies,
+/// so this lays out each `fuse_direntplus` itself: an entry reply followed by a `fuse_dirent`,
+/// padded to 8 bytes.
+struct ReaddirPlusOut {
+    buf: Vec<u8>,
+    /// The most the kernel asked for, in bytes
+    limit: usize,
+}
+
+impl ReaddirPlusOut {
+    fn new(limit: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(limit),
+            limit,
+        }
+    }
+
+    /// Mirrors `reply::ReaddirOut::entry`, returning true without adding `entry` if it doesn't
+    /// fit
+    fn entry(&mut self, entry: DirEntryPlus) -> bool {
+        // ino, off, namelen and type
+        const DIRENT_SIZE: usize = 8 + 8 + 4 + 4;
+
+        let DirEntryPlus { entry, lookup } = entry;
+        let entry_out = reply::EntryOut::from(lookup);
+        let name = entry.name.as_bytes();
+
+        let size = bytes::Bytes::size(&entry_out) + DIRENT_SIZE + name.len();
+        let aligned_size = (size + 7) & !7;
+
+        // the allocation may be larger than asked for, so it can't stand in for the limit
+        if self.buf.len() + aligned_size > self.limit {
+            return true;
+        }
+
+        let start = self.buf.len();
+        bytes::Bytes::fill_bytes(&entry_out, &mut ExtendBytes(&mut self.buf));
+
+        self.buf
+            .extend_from_slice(&entry.inode.to_u64().to_ne_bytes());
+        self.buf.extend_from_slice(&entry.offset.to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(name.len() as u32).to_ne_bytes());
+        self.buf
+            .extend_from_slice(&(entry.typ.to_libc_type() as u32).to_ne_bytes());
+        self.buf.extend_from_slice(name);
+        self.buf.resize(start + aligned_size, 0);
+
+        false
+    }
+}
+
+/// Collects a reply's chunks into a buffer
+struct ExtendBytes<'b>(&'b mut Vec<u8>);
+
+impl<'a> FillBytes<'a> for ExtendBytes<'_> {
+    fn put(&mut self, chunk: &'a [u8]) {
+        self.0.extend_from_slice(chunk);
+    }
+}
+
+/// Sets every capability flag on `config` to match `capabilities`
+pub(crate) fn apply_capabilities(config: &mut KernelConfig, capabilities: InitCapabilities) {
+    let has = |cap| capabilities.contains(cap);
+
+    config
+        .async_read(has(InitCapabilities::ASYNC_READ))
+        .posix_locks(has(InitCapabilities::POSIX_LOCKS))
+        .atomic_o_trunc(has(InitCapabilities::ATOMIC_O_TRUNC))
+        .export_support(has(InitCapabilities::EXPORT_SUPPORT))
+        .dont_mask(has(InitCapabilities::DONT_MASK))
+        .flock_locks(has(InitCapabilities::FLOCK_LOCKS))
+        .auto_inval_data(has(InitCapabilities::AUTO_INVAL_DATA))
+        .readdirplus(has(InitCapabilities::READDIRPLUS))
+        .readdirplus_auto(has(InitCapabilities::READDIRPLUS_AUTO))
+        .async_dio(has(InitCapabilities::ASYNC_DIO))
+        .writeback_cache(has(InitCapabilities::WRITEBACK_CACHE))
+        .parallel_dirops(has(InitCapabilities::PARALLEL_DIROPS))
+        .handle_killpriv(has(InitCapabilities::HANDLE_KILLPRIV))
+        .posix_acl(has(InitCapabilities::POSIX_ACL));
+}
+
+impl<T> From<&Operation<'_, T>> for OpKind {
+    fn from(op: &Operation<'_, T>) -> Self {
+        match op {
+            Operation::Lookup(_) => Self::Lookup,
+            Operation::Getattr(_) => Self::Getattr,
+            Operation::Setattr(_) => Self::Setattr,
+            Operation::Readlink(_) => Self::Readlink,
+            Operation::Symlink(_) => Self::Symlink,
+            Operation::Mknod(_) => Self::Mknod,
+            Operation::Mkdir(_) => Self::Mkdir,
+            Operation::Unlink(_) => Self::Unlink,
+            Operation::Rmdir(_) => Self::Rmdir,
+            Operation::Rename(_) => Self::Rename,
+            Operation::Link(_) => Self::Link,
+            Operation::Open(_) => Self::Open,
+            Operation::Read(_) => Self::Read,
+            Operation::Write(_, _) => Self::Write,
+            Operation::Release(_) => Self::Release,
+            Operation::Statfs(_) => Self::Statfs,
+            Operation::Fsync(_) => Self::Fsync,
+            Operation::Setxattr(_) => Self::Setxattr,
+            Operation::Getxattr(_) => Self::Getxattr,
+            Operation::Listxattr(_) => Self::Listxattr,
+            Operation::Removexattr(_) => Self::Removexattr,
+            Operation::Flush(_) => Self::Flush,
+            Operation::Opendir(_) => Self::Opendir,
+            Operation::Readdir(_) => Self::Readdir,
+            Operation::Releasedir(_) => Self::Releasedir,
+            Operation::Fsyncdir(_) => Self::Fsyncdir,
+            Operation::Getlk(_) => Self::Getlk,
+            Operation::Setlk(_) => Self::Setlk,
+            Operation::Flock(_) => Self::Flock,
+            Operation::Access(_) => Self::Access,
+            Operation::Create(_) => Self::Create,
+            Operation::Bmap(_) => Self::Bmap,
+            Operation::Fallocate(_) => Self::Fallocate,
+            Operation::CopyFileRange(_) => Self::CopyFileRange,
+            Operation::Poll(_) => Self::Poll,
+            Operation::Forget(_) => Self::Forget,
+            Operation::Interrupt(_) => Self::Interrupt,
+            Operation::NotifyReply(_, _) => Self::NotifyReply,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// The outcome of a single `handle_*` call.
+///
+/// The outer `Result` covers failures talking to the kernel, while the inner `FSResult` is what
+/// the filesystem itself returned and is what gets handed to `Filesystem::post_op`.
+type HandlerResult = Result<FSResult<()>, PolyfuseError>;
+
+/// How long each kind of operation may take, with a default for kinds without their own entry.
+///
+/// What happens on an overrun depends on the runner:
+///
+/// - `Runner::with_timeout_policy` only logs a warning, including while the call is still
+///   running. The runner calls into the filesystem on the session thread while holding it
+///   mutably, and a thread can't be stopped from outside, so a stuck call holds up the session
+///   until it returns. Replying early instead would tell the kernel an operation failed which
+///   goes on to take effect anyway, so the call's own result is always what gets sent.
+/// - `AsyncRunner::with_timeout_policy` drops the operation's future and replies `ETIMEDOUT`,
+///   for operations `AsyncFilesystem::can_time_out` allows. `BlockingAdapter` only allows
+///   read-only ones, since a blocking call carries on even once its future is dropped.
+///
+/// `EINTR` is never used, since it tells callers a signal interrupted them and most retry it
+/// straight away, which would only queue up the same slow operation again.
+#[derive(Debug, Clone)]
+pub struct TimeoutPolicy {
+    /// Timeout applied to every operation without an entry in `per_op`
+    pub default: Duration,
+    pub per_op: HashMap<OpKind, Duration>,
+}
+
+impl TimeoutPolicy {
+    pub fn new(default: Duration) -> TimeoutPolicy {
+        TimeoutPolicy {
+            default,
+            per_op: HashMap::new(),
+        }
+    }
+
+    /// Returns the timeout which applies to `op`
+    pub fn timeout_for(&self, op: OpKind) -> Duration {
+        self.per_op.get(&op).copied().unwrap_or(self.default)
+    }
+}
+
+/// Commonly used mount options, applied on top of the runner's `KernelConfig` when mounting.
+/// Anything left unset keeps polyfuse's default. See `Runner::new_with_config` and
+/// `AsyncRunner::with_config`.
+#[derive(Debug, Clone, Default, TypedBuilder)]
+pub struct RunnerConfig {
+    /// The most the kernel reads ahead, in bytes
+    #[builder(default, setter(strip_option))]
+    pub max_readahead: Option<u32>,
+
+    /// The largest write the kernel sends at once, in bytes
+    #[builder(default, setter(strip_option))]
+    pub max_write: Option<u32>,
+
+    /// The granularity of timestamps, in nanoseconds
+    #[builder(default, setter(strip_option))]
+    pub time_gran: Option<u32>,
+
+    /// How many background requests the kernel may queue
+    #[builder(default, setter(strip_option))]
+    pub max_background: Option<u16>,
+
+    /// How many background requests the kernel queues before it considers the filesystem
+    /// congested
+    #[builder(default, setter(strip_option))]
+    pub congestion_threshold: Option<u16>,
+
+    /// Lets users other than the one mounting access the filesystem. Needs `user_allow_other`
+    /// in `/etc/fuse.conf` when not mounting as root.
+    #[builder(default)]
+    pub allow_other: bool,
+
+    /// Unmounts the filesystem if the process exits without doing so
+    #[builder(default)]
+    pub auto_unmount: bool,
+
+    /// How long any single operation may take. Shorthand for `with_timeout_policy` with
+    /// `TimeoutPolicy::new`, and ignored if a policy is set. `Runner` only logs overruns, while
+    /// `AsyncRunner` replies `ETIMEDOUT`; see `TimeoutPolicy`.
+    #[builder(default, setter(strip_option))]
+    pub operation_timeout: Option<Duration>,
+}
+
+impl RunnerConfig {
+    pub(crate) fn apply(&self, config: &mut KernelConfig) {
+        if let Some(x) = self.max_readahead {
+            config.max_readahead(x);
+        }
+
+        if let Some(x) = self.max_write {
+            config.max_write(x);
+        }
+
+        if let Some(x) = self.time_gran {
+            config.time_gran(x);
+        }
+
+        if let Some(x) = self.max_background {
+            config.max_background(x);
+        }
+
+        if let Some(x) = self.congestion_threshold {
+            config.congestion_threshold(x);
+        }
+
+        if self.allow_other {
+            config.mount_option("allow_other");
+        }
+
+        config.auto_unmount(self.auto_unmount);
+    }
+}
+
+type FsBuilder<T> = Box<dyn FnOnce(&Path) -> T + Send>;
+
+/// The filesystem a `Runner` drives, which might not have been built yet
+enum LazyFs<T> {
+    Ready(T),
+    Pending(FsBuilder<T>),
+    /// Only left behind if the builder panicked
+    Poisoned,
+}
+
+impl<T> LazyFs<T> {
+    /// Returns the filesystem, building it first if needed
+    fn force(&mut self, mountpoint: &Path) -> &mut T {
+        if let LazyFs::Pending(_) = self {
+            if let LazyFs::Pending(builder) = std::mem::replace(self, LazyFs::Poisoned) {
+                *self = LazyFs::Ready(builder(mountpoint));
+            }
+        }
+
+        match self {
+            LazyFs::Ready(fs) => fs,
+            _ => panic!("the filesystem builder panicked"),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for LazyFs<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LazyFs::Ready(fs) => fs.fmt(f),
+            LazyFs::Pending(_) => f.write_str("<not built yet>"),
+            LazyFs::Poisoned => f.write_str("<poisoned>"),
+        }
+    }
+}
+
+/// Mounts a `Filesystem` and dispatches the kernel's requests to it.
+///
+/// Besides `Runner::new`, runners can be configured all at once with `Runner::builder()`, which
+/// only requires `fs` and `mountpoint`. Options set through `kernel_config` are passed to the
+/// kernel as-is, apart from the capabilities covered by `InitCapabilities`, which are replaced
+/// by whatever `Filesystem::init` agrees to.
+///
+/// polyfuse's `KernelConfig` is consumed by mounting and can't be cloned, so one set through
+/// `kernel_config` only lasts for a single mount, and mounting the same runner again fails.
+/// Options set through `config` are applied afresh on every mount.
+#[derive(TypedBuilder)]
 pub struct Runner<T>
 where
     T: Filesystem,
 {
+    #[builder(setter(into))]
     mountpoint: PathBuf,
-    fs: T,
+
+    #[builder(setter(transform = |fs: T| LazyFs::Ready(fs)))]
+    fs: LazyFs<T>,
+
+    #[builder(default, setter(strip_option))]
+    kernel_config: Option<KernelConfig>,
+
+    /// Whether `kernel_config` has been used up by a mount
+    #[builder(default, setter(skip))]
+    kernel_config_used: bool,
+
+    /// Applied on top of `kernel_config` on every mount
+    #[builder(default)]
+    config: RunnerConfig,
+
+    #[builder(default, setter(strip_option))]
+    timeout_policy: Option<TimeoutPolicy>,
+
+    /// The capabilities offered to `Filesystem::init`
+    #[builder(default)]
+    capabilities: InitCapabilities,
+
+    /// See `Runner::with_export_support`
+    #[builder(default)]
+    export_support: bool,
+
+    /// See `Runner::with_metrics`
+    #[builder(default, setter(strip_option))]
+    metrics: Option<Arc<RunnerMetrics>>,
+    /// Set by `Runner::mount`, and taken by `run_block` to serve requests from
+    #[builder(default, setter(skip))]
+    session: Option<Session>,
+    /// Whether the kernel agreed to writeback caching for the current mount
+    #[builder(default, setter(skip))]
+    writeback_cache: bool,
+
+    /// Feeds `watch_timeouts` while serving with a timeout policy
+    #[builder(default, setter(skip))]
+    watchdog: Option<Sender<Watch>>,
+}
+
+impl<T: Filesystem + fmt::Debug> fmt::Debug for Runner<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // KernelConfig doesn't implement Debug
+        f.debug_struct("Runner")
+            .field("mountpoint", &self.mountpoint)
+            .field("fs", &self.fs)
+            .field("config", &self.config)
+            .field("timeout_policy", &self.timeout_policy)
+            .field("capabilities", &self.capabilities)
+            .field("export_support", &self.export_support)
+            .field("metrics", &self.metrics)
+            .field("mounted", &self.session.is_some())
+            .finish_non_exhaustive()
+    }
 }
 
 impl<T: Filesystem> Runner<T> {
     pub fn new<P: AsRef<Path>>(fs: T, mountpoint: P) -> Runner<T> {
+        Runner::builder()
+            .fs(fs)
+            .mountpoint(mountpoint.as_ref())
+            .build()
+    }
+
+    /// Like `new`, but mounts with the options in `config`
+    pub fn new_with_config<P: AsRef<Path>>(
+        fs: T,
+        mountpoint: P,
+        config: RunnerConfig,
+    ) -> Runner<T> {
+        let mut runner = Runner::builder()
+            .fs(fs)
+            .mountpoint(mountpoint.as_ref())
+            .build();
+        runner.config = config;
+
+        runner
+    }
+
+    /// Like `new`, but defers building the filesystem until just before mounting, once the
+    /// mountpoint is known to be a directory. `builder` is given the mountpoint.
+    ///
+    /// Useful for filesystems whose setup is expensive or has side effects, such as opening
+    /// backing files or connecting to a server.
+    pub fn with_fs_builder<P, F>(mountpoint: P, builder: F) -> Runner<T>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&Path) -> T + Send + 'static,
+    {
         Runner {
             mountpoint: mountpoint.as_ref().to_path_buf(),
-            fs,
+            fs: LazyFs::Pending(Box::new(builder)),
+            kernel_config: None,
+            kernel_config_used: false,
+            config: RunnerConfig::default(),
+            timeout_policy: None,
+            capabilities: InitCapabilities::default(),
+            export_support: false,
+            metrics: None,
+            session: None,
+            writeback_cache: false,
+            watchdog: None,
         }
     }
 
+    /// Checks every operation against `policy`. See `TimeoutPolicy` for how overruns are handled.
+    pub fn with_timeout_policy(mut self, policy: TimeoutPolicy) -> Runner<T> {
+        self.timeout_policy = Some(policy);
+        self
+    }
+
+    /// Asks the kernel to cache writes and flush them to the filesystem later. See
+    /// `Filesystem::write` for how this changes the writes you receive. Disabled by default.
+    pub fn with_writeback_cache(mut self, enabled: bool) -> Runner<T> {
+        self.capabilities
+            .set(InitCapabilities::WRITEBACK_CACHE, enabled);
+        self
+    }
+
+    /// Tells the kernel the filesystem can be exported over NFS. The kernel then looks inodes
+    /// up by handle by sending lookups of `.` and `..`. `.` is answered by the runner with
+    /// `getattr`, while `..` is passed to `Filesystem::lookup` and must return the parent.
+    ///
+    /// See the `export` module for encoding inodes into file handles.
+    pub fn with_export_support(mut self, enabled: bool) -> Runner<T> {
+        self.export_support = enabled;
+        self
+    }
+
+    /// Counts every operation handled in `metrics`. Keep a clone of the `Arc` to read the
+    /// counters from another thread while the runner is serving requests.
+    pub fn with_metrics(mut self, metrics: Arc<RunnerMetrics>) -> Runner<T> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The metrics set with `with_metrics`, if any
+    pub fn metrics(&self) -> Option<&RunnerMetrics> {
+        self.metrics.as_deref()
+    }
+
+    /// Calls `Filesystem::init` and mounts the filesystem without serving any requests yet,
+    /// so the session is available from `session` before `run_block`. Does nothing if already
+    /// mounted.
+    ///
+    /// Until a `run_block*` call takes over, requests are left waiting in the kernel, so
+    /// anything touching the mountpoint blocks. Dropping the runner unmounts the filesystem
+    /// without calling `Filesystem::destroy`.
+    pub fn mount(&mut self) -> Result<()> {
+        if self.session.is_some() {
+            return Ok(());
+        }
+
+        if !self.mountpoint.is_dir() {
+            let msg = format!("{} is not a directory", self.mountpoint.display());
+            return Err(io::Error::new(io::ErrorKind::NotFound, msg).into());
+        }
+
+        // The session takes ownership of the config, and it can't be cloned for the next mount
+        let mut config = match self.kernel_config.take() {
+            Some(config) => {
+                self.kernel_config_used = true;
+                config
+            }
+            None if self.kernel_config_used => {
+                let msg = "a KernelConfig only lasts for one mount, use RunnerConfig to remount";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+            }
+            None => KernelConfig::default(),
+        };
+        self.config.apply(&mut config);
+
+        if self.timeout_policy.is_none() {
+            self.timeout_policy = self.config.operation_timeout.map(TimeoutPolicy::new);
+        }
+
+        let mut offered = self.capabilities;
+        if self.export_support {
+            offered.insert(InitCapabilities::EXPORT_SUPPORT);
+        }
+
+        let capabilities = self
+            .fs
+            .force(&self.mountpoint)
+            .init(offered)
+            .map_err(|e| Error::InitError(e.into()))?;
+
+        // `.` lookups are only answered specially if the filesystem kept export support
+        self.export_support = capabilities.contains(InitCapabilities::EXPORT_SUPPORT);
+        self.writeback_cache = capabilities.contains(InitCapabilities::WRITEBACK_CACHE);
+
+        apply_capabilities(&mut config, capabilities);
+
+        self.session = Some(Session::mount(self.mountpoint.to_path_buf(), config)?);
+        Ok(())
+    }
+
+    /// The polyfuse session, for calling polyfuse APIs this crate doesn't wrap. Only set
+    /// between `mount` and the following `run_block*` call; clone its `notifier` to send
+    /// notifications while the filesystem is being served.
+    pub fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    /// Mounts the filesystem, unless `mount` already did, and serves requests until it is
+    /// unmounted.
+    ///
+    /// The mount only lives as long as this call. It is torn down when this returns, including
+    /// by panicking, so once this returns the runner no longer holds a mount.
     pub fn run_block(&mut self) -> Result<()> {
-        let session = Session::mount(self.mountpoint.to_path_buf(), KernelConfig::default())?;
+        self.mount_and_serve(None)
+    }
+
+    /// Like `run_block`, but unmounts and returns `Ok(())` once anything is sent on `shutdown`.
+    /// Dropping the sender leaves the filesystem mounted until it is unmounted externally.
+    pub fn run_block_with_shutdown(&mut self, shutdown: Receiver<()>) -> Result<()> {
+        self.mount_and_serve(Some(shutdown))
+    }
+
+    /// Like `run_block`, but unmounts cleanly and returns `Ok(())` on `SIGINT` or `SIGTERM`,
+    /// calling `Filesystem::destroy` as usual. The signals' default handling is replaced while
+    /// this runs.
+    pub fn run_block_with_signals(&mut self) -> Result<()> {
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        let handle = signals.handle();
+        let (sender, shutdown) = mpsc::channel();
+
+        let listener = thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!("received signal {}, unmounting", signal);
+                let _ = sender.send(());
+            }
+        });
+
+        let result = self.run_block_with_shutdown(shutdown);
+
+        // stops the listener if the session ended some other way
+        handle.close();
+        let _ = listener.join();
+
+        result
+    }
+
+    fn mount_and_serve(&mut self, shutdown: Option<Receiver<()>>) -> Result<()> {
+        self.mount()?;
+        let session = self
+            .session
+            .take()
+            .expect("mount succeeded without a session");
+
+        let watchdog = self.timeout_policy.is_some().then(|| {
+            let (sender, events) = mpsc::channel();
+            self.watchdog = Some(sender);
+            thread::spawn(move || watch_timeouts(events))
+        });
+
+        let result = match shutdown {
+            None => self.serve(&session),
+            Some(shutdown) => {
+                let done = AtomicBool::new(false);
+                let mountpoint = self.mountpoint.clone();
+
+                thread::scope(|scope| {
+                    let (done, mountpoint) = (&done, &mountpoint);
+                    scope.spawn(move || wait_for_shutdown(shutdown, done, mountpoint));
+
+                    let result = self.serve(&session);
+                    done.store(true, Ordering::Relaxed);
+                    result
+                })
+            }
+        };
 
-        while let Some(req) = session.next_request()? {
-            match req.operation().map_err(PolyfuseError::DecodeError)? {
+        // dropping the sender stops the watchdog
+        self.watchdog = None;
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.join();
+        }
+
+        // Runs whether the session ended normally or not, so state can still be saved
+        self.fs.force(&self.mountpoint).destroy();
+
+        result
+    }
+
+    /// Dispatches requests until the session ends
+    fn serve(&mut self, session: &Session) -> Result<()> {
+        let notifier = session.notifier();
+
+        loop {
+            let req = match session.next_request() {
+                Ok(Some(req)) => req,
+                // the session was unmounted
+                Ok(None) => break,
+                // a signal arrived while waiting, which shouldn't end the session
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    error!("failed to read the next request: {}", e);
+                    return Err(e.into());
+                }
+            };
+
+            let op = req.operation().map_err(PolyfuseError::DecodeError)?;
+            let kind = OpKind::from(&op);
+            let start = Instant::now();
+
+            if let Err(e) = self
+                .fs
+                .force(&self.mountpoint)
+                .pre_op(&kind)
+                .map_err(Into::<FSError>::into)
+            {
+                warn!("pre_op rejected {:?}: {:#?}", kind, e);
+
+                // forgets are never replied to
+                if kind != OpKind::Forget {
+                    req.reply_error(e.to_libc_error())
+                        .map_err(PolyfuseError::ReplyErrError)?;
+                }
+
+                if let Some(metrics) = &self.metrics {
+                    metrics.record(kind, false, start.elapsed());
+                }
+
+                continue;
+            }
+
+            let result = match op {
+                Operation::Forget(forgets) => self.handle_forget(&forgets),
                 Operation::Open(op) => self.handle_open(&req, op)?,
                 Operation::Opendir(op) => self.handle_opendir(&req, op)?,
+                Operation::CopyFileRange(op) => self.handle_copy_file_range(&req, op)?,
+                Operation::Fallocate(op) => self.handle_fallocate(&req, op)?,
+                Operation::Fsync(op) => self.handle_fsync(&req, op)?,
+                Operation::Fsyncdir(op) => self.handle_fsyncdir(&req, op)?,
+                Operation::Flush(op) => self.handle_flush(&req, op)?,
+                Operation::Release(op) => self.handle_release(&req, op)?,
+                Operation::Releasedir(op) => self.handle_releasedir(&req, op)?,
 
                 Operation::Setxattr(op) => self.handle_setxattr(&req, op)?,
                 Operation::Getxattr(op) => self.handle_getxattr(&req, op)?,
                 Operation::Listxattr(op) => self.handle_listxattr(&req, op)?,
 
+                Operation::Create(op) => self.handle_create(&req, op)?,
                 Operation::Mknod(op) => self.handle_mknod(&req, op)?,
                 Operation::Mkdir(op) => self.handle_mkdir(&req, op)?,
+                Operation::Rename(op) => self.handle_rename(&req, op)?,
+                Operation::Link(op) => self.handle_link(&req, op)?,
+                Operation::Unlink(op) => self.handle_unlink(&req, op)?,
+                Operation::Rmdir(op) => self.handle_rmdir(&req, op)?,
+                Operation::Symlink(op) => self.handle_symlink(&req, op)?,
+                Operation::Readlink(op) => self.handle_readlink(&req, op)?,
 
                 Operation::Lookup(op) => self.handle_lookup(&req, op)?,
                 Operation::Getattr(op) => self.handle_getattr(&req, op)?,
                 Operation::Setattr(op) => self.handle_setattr(&req, op)?,
                 Operation::Readdir(op) => self.handle_readdir(&req, op)?,
                 Operation::Read(op) => self.handle_read(&req, op)?,
-                Operation::Write(op, buf) => self.handle_write(&req, op, buf)?,
+                Operation::Write(op, buf) => self.handle_write(&req, &notifier, op, buf)?,
+                Operation::Access(op) => self.handle_access(&req, op)?,
+                Operation::Statfs(op) => self.handle_statfs(&req, op)?,
                 op => {
                     error!("unimplemented: {:?}", op);
                     req.reply_error(FSError::NotImplemented.to_libc_error())
                         .map_err(PolyfuseError::ReplyErrError)?;
+
+                    Err(FSError::NotImplemented)
+                }
+            };
+
+            if let Some(metrics) = &self.metrics {
+                metrics.record(kind, result.is_ok(), start.elapsed());
+            }
+
+            self.fs.force(&self.mountpoint).post_op(&kind, &result);
+        }
+
+        Ok(())
+    }
+
+    /// Runs `f` against the filesystem, logging it if it overruns the timeout policy for `op`.
+    pub(crate) fn call<'a, R>(
+        &'a mut self,
+        op: OpKind,
+        f: impl FnOnce(&'a mut T) -> FSResult<R, T::Error>,
+    ) -> FSResult<R> {
+        let Runner {
+            fs,
+            timeout_policy,
+            mountpoint,
+            watchdog,
+            ..
+        } = self;
+        let fs = fs.force(mountpoint);
+
+        let timeout = match timeout_policy {
+            Some(policy) => policy.timeout_for(op),
+            None => return f(fs).map_err(Into::into),
+        };
+
+        // the watchdog only runs while serving, not for dry runs
+        if let Some(watchdog) = watchdog {
+            let span = tracing::Span::current();
+            let _ = watchdog.send(Watch::Started { op, timeout, span });
+        }
+
+        let start = Instant::now();
+        let result = f(fs).map_err(Into::into);
+        let elapsed = start.elapsed();
+
+        if let Some(watchdog) = watchdog {
+            let _ = watchdog.send(Watch::Finished);
+        }
+
+        if elapsed > timeout {
+            warn!(
+                "{:?} took {:?}, exceeding its timeout of {:?}",
+                op, elapsed, timeout
+            );
+        }
+
+        result
+    }
+
+    fn handle_forget(&mut self, forgets: &[op::Forget]) -> FSResult<()> {
+        let _span = info_span!("forget", count = forgets.len()).entered();
+
+        self.call(OpKind::Forget, |fs| {
+            match forgets {
+                [forget] => fs.forget(forget.ino().into(), forget.nlookup()),
+                forgets => {
+                    let nodes: Vec<_> = forgets
+                        .iter()
+                        .map(|forget| (forget.ino().into(), forget.nlookup()))
+                        .collect();
+
+                    fs.batch_forget(&nodes);
                 }
             }
-        }
 
-        todo!()
+            Ok(())
+        })
+    }
+
+    fn handle_open(&mut self, req: &Request, op: op::Open<'_>) -> HandlerResult {
+        let _span = info_span!("open", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Open, |fs| fs.open(op.ino().into(), op.flags())) {
+            Ok(obj) => {
+                let res = reply::OpenOut::from(obj);
+                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("open error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_opendir(&mut self, req: &Request, op: op::Opendir<'_>) -> HandlerResult {
+        let _span = info_span!("opendir", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Opendir, |fs| {
+            fs.open_dir(op.ino().into(), op.flags())
+        }) {
+            Ok(obj) => {
+                let res = reply::OpenOut::from(obj);
+                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("opendir error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_copy_file_range(
+        &mut self,
+        req: &Request,
+        op: op::CopyFileRange<'_>,
+    ) -> HandlerResult {
+        let _span = info_span!(
+            "copy_file_range",
+            ino = op.ino_in(),
+            ino_out = op.ino_out(),
+            len = op.length()
+        )
+        .entered();
+
+        let result = match self.call(OpKind::CopyFileRange, |fs| {
+            fs.copy_file_range(
+                op.ino_in().into(),
+                Filehandle::from_raw(op.fh_in()),
+                op.offset_in(),
+                op.ino_out().into(),
+                Filehandle::from_raw(op.fh_out()),
+                op.offset_out(),
+                op.length(),
+                op.flags(),
+            )
+        }) {
+            Ok(copied) => {
+                // the kernel never asks for more than fits in a u32
+                let mut rep = reply::WriteOut::default();
+                rep.size(copied.min(u32::MAX as u64) as u32);
+
+                req.reply(rep).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("copy_file_range error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_fallocate(&mut self, req: &Request, op: op::Fallocate<'_>) -> HandlerResult {
+        let _span = info_span!("fallocate", ino = op.ino()).entered();
+
+        let mode = match FallocateMode::from_libc_type(op.mode()) {
+            Some(mode) => mode,
+            None => {
+                let e = FSError::InvalidFlags(op.mode());
+
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                return Ok(Err(e));
+            }
+        };
+
+        let fh = Filehandle::from_raw(op.fh());
+
+        let result = match self.call(OpKind::Fallocate, |fs| {
+            fs.fallocate(op.ino().into(), fh, op.offset(), op.length(), mode)
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("fallocate error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_fsync(&mut self, req: &Request, op: op::Fsync<'_>) -> HandlerResult {
+        let _span = info_span!("fsync", ino = op.ino()).entered();
+
+        let fh = Filehandle::from_raw(op.fh());
+
+        let result = match self.call(OpKind::Fsync, |fs| {
+            fs.fsync(op.ino().into(), fh, op.datasync())
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("fsync error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_fsyncdir(&mut self, req: &Request, op: op::Fsyncdir<'_>) -> HandlerResult {
+        let _span = info_span!("fsyncdir", ino = op.ino()).entered();
+
+        let fh = Filehandle::from_raw(op.fh());
+
+        let result = match self.call(OpKind::Fsyncdir, |fs| {
+            fs.fsyncdir(op.ino().into(), fh, op.datasync())
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("fsyncdir error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_flush(&mut self, req: &Request, op: op::Flush<'_>) -> HandlerResult {
+        let _span = info_span!("flush", ino = op.ino()).entered();
+
+        let fh = Filehandle::from_raw(op.fh());
+
+        let result = match self.call(OpKind::Flush, |fs| {
+            fs.flush(op.ino().into(), fh, op.lock_owner().into_raw())
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("flush error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
     }
 
-    fn handle_open(&mut self, req: &Request, op: op::Open<'_>) -> Result<(), PolyfuseError> {
-        match self.fs.open(op.ino().into(), op.flags()) {
-            Ok(obj) => {
-                let mut res = reply::OpenOut::default();
+    fn handle_release(&mut self, req: &Request, op: op::Release<'_>) -> HandlerResult {
+        let _span = info_span!("release", ino = op.ino()).entered();
 
-                res.fh(obj.handle.to_raw());
-                res.direct_io(obj.direct_io);
-                res.keep_cache(obj.keep_cache);
-                res.nonseekable(!obj.seekable);
-                res.cache_dir(false); // I think this only works for readdir
+        let fh = Filehandle::from_raw(op.fh());
 
-                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+        let result = match self.call(OpKind::Release, |fs| {
+            fs.release(op.ino().into(), fh, op.flags(), op.flush())
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
-                warn!("open error occured: {:#?}", e);
+                warn!("release error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
-    fn handle_opendir(&mut self, req: &Request, op: op::Opendir<'_>) -> Result<(), PolyfuseError> {
-        match self.fs.open_dir(op.ino().into(), op.flags()) {
-            Ok(obj) => {
-                let mut res = reply::OpenOut::default();
+    fn handle_releasedir(&mut self, req: &Request, op: op::Releasedir<'_>) -> HandlerResult {
+        let _span = info_span!("releasedir", ino = op.ino()).entered();
 
-                res.fh(obj.handle.to_raw());
-                res.direct_io(obj.direct_io);
-                res.keep_cache(obj.keep_cache);
-                res.nonseekable(!obj.seekable);
-                res.cache_dir(obj.cache_dir);
+        let fh = Filehandle::from_raw(op.fh());
 
-                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+        let result = match self.call(OpKind::Releasedir, |fs| {
+            fs.releasedir(op.ino().into(), fh, op.flags())
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
-                warn!("opendir error occured: {:#?}", e);
+                warn!("releasedir error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
-    fn handle_setxattr(
-        &mut self,
-        req: &Request,
-        op: op::Setxattr<'_>,
-    ) -> Result<(), PolyfuseError> {
-        let flags = SetXAttrFlags::from_libc_type(op.flags() as i32)
-            .ok_or_else(|| FSError::InvalidFlags(op.flags() as u32));
+    fn handle_setxattr(&mut self, req: &Request, op: op::Setxattr<'_>) -> HandlerResult {
+        let _span = info_span!("setxattr", ino = op.ino()).entered();
 
-        if flags.is_err() {
-            req.reply_error(libc::EINVAL)
-                .map_err(PolyfuseError::ReplyErrError)?;
+        let flags = match SetXAttrFlags::from_libc_type(op.flags() as i32) {
+            Some(flags) => flags,
+            None => {
+                let e = FSError::InvalidFlags(op.flags());
 
-            return Ok(());
-        }
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
 
-        match self
-            .fs
-            .setxattr(op.ino().into(), op.name(), op.value(), flags.unwrap())
-        {
+                return Ok(Err(e));
+            }
+        };
+
+        let result = match self.call(OpKind::Setxattr, |fs| {
+            fs.setxattr(op.ino().into(), op.name(), op.value(), flags)
+        }) {
             Ok(_) => {
                 req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("setxattr error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
-    fn handle_getxattr(
-        &mut self,
-        req: &Request,
-        op: op::Getxattr<'_>,
-    ) -> Result<(), PolyfuseError> {
+    fn handle_getxattr(&mut self, req: &Request, op: op::Getxattr<'_>) -> HandlerResult {
+        let _span = info_span!("getxattr", ino = op.ino()).entered();
+
         let size = op.size();
 
-        match self.fs.getxattr(op.ino().into(), op.name(), size) {
+        let result = match self.call(OpKind::Getxattr, |fs| {
+            fs.getxattr_owned(op.ino().into(), op.name(), size)
+        }) {
             Ok(obj) => {
                 if size == 0 {
                     // When op.size() == 0, polyfuse wants us to return the length of the attribute
@@ -201,31 +1258,33 @@ impl<T: Filesystem> Runner<T> {
                     req.reply(res).map_err(PolyfuseError::ReplyError)?;
                 } else {
                     assert!(
-                        obj.data.len() <= size as usize,
+                        obj.data().len() <= size as usize,
                         "cannot return data larger than requested"
                     );
 
-                    req.reply(obj.data).map_err(PolyfuseError::ReplyError)?;
+                    req.reply(obj.data()).map_err(PolyfuseError::ReplyError)?;
                 }
+
+                Ok(())
             }
             Err(e) => {
                 warn!("getxattr error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
-    fn handle_listxattr(
-        &mut self,
-        req: &Request,
-        op: op::Listxattr<'_>,
-    ) -> Result<(), PolyfuseError> {
+    fn handle_listxattr(&mut self, req: &Request, op: op::Listxattr<'_>) -> HandlerResult {
+        let _span = info_span!("listxattr", ino = op.ino()).entered();
+
         let size = op.size();
 
-        match self.fs.listxattrs(op.ino().into(), size) {
+        let result = match self.call(OpKind::Listxattr, |fs| fs.listxattrs(op.ino().into(), size)) {
             Ok(obj) => {
                 if size == 0 {
                     let mut res = reply::XattrOut::default();
@@ -239,69 +1298,185 @@ impl<T: Filesystem> Runner<T> {
 
                     req.reply(obj.0).map_err(PolyfuseError::ReplyError)?;
                 }
+
+                Ok(())
             }
             Err(e) => {
                 warn!("listxattr error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
-    fn handle_mknod(&mut self, req: &Request, op: op::Mknod<'_>) -> Result<(), PolyfuseError> {
+    fn handle_mknod(&mut self, req: &Request, op: op::Mknod<'_>) -> HandlerResult {
+        let _span = info_span!("mknod", parent = op.parent(), name = ?op.name()).entered();
+
         let parent = op.parent().into();
 
-        match self.fs.make_node(parent, op.name(), op.mode(), op.rdev()) {
+        let result = match self.call(OpKind::Mknod, |fs| {
+            fs.make_node(parent, op.name(), op.mode(), op.rdev())
+        }) {
             Ok(obj) => {
                 let res = reply::EntryOut::from(obj);
                 req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("mknod error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
+    }
+
+    fn handle_create(&mut self, req: &Request, op: op::Create<'_>) -> HandlerResult {
+        let _span = info_span!("create", parent = op.parent(), name = ?op.name()).entered();
+
+        let exclusive = op.open_flags() & libc::O_EXCL as u32 != 0;
+        let result = match self.call(OpKind::Create, |fs| {
+            if exclusive {
+                fs.create_exclusive(op.parent().into(), op.name(), op.mode())
+            } else {
+                fs.create(op.parent().into(), op.name(), op.mode(), op.open_flags())
+            }
+        }) {
+            Ok(obj) => {
+                let entry = reply::EntryOut::from(obj.lookup);
+                let open = reply::OpenOut::from(obj.open);
+
+                req.reply((entry, open))
+                    .map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("create error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
     }
 
-    fn handle_mkdir(&mut self, req: &Request, op: op::Mkdir<'_>) -> Result<(), PolyfuseError> {
-        match self.fs.make_dir(op.parent().into(), op.name(), op.mode()) {
+    fn handle_mkdir(&mut self, req: &Request, op: op::Mkdir<'_>) -> HandlerResult {
+        let _span = info_span!("mkdir", parent = op.parent(), name = ?op.name()).entered();
+
+        let result = match self.call(OpKind::Mkdir, |fs| {
+            fs.make_dir(op.parent().into(), op.name(), op.mode())
+        }) {
             Ok(obj) => {
                 let res = reply::EntryOut::from(obj);
                 req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("mkdir error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
+    }
+
+    fn handle_symlink(&mut self, req: &Request, op: op::Symlink<'_>) -> HandlerResult {
+        let _span = info_span!("symlink", parent = op.parent(), name = ?op.name()).entered();
+
+        let result = match self.call(OpKind::Symlink, |fs| {
+            fs.make_symlink(op.parent().into(), op.name(), op.link())
+        }) {
+            Ok(obj) => {
+                let res = reply::EntryOut::from(obj);
+                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("symlink error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_readlink(&mut self, req: &Request, op: op::Readlink<'_>) -> HandlerResult {
+        let _span = info_span!("readlink", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Readlink, |fs| fs.readlink(op.ino().into())) {
+            Ok(target) => {
+                req.reply(target).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("readlink error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
     }
 
-    fn handle_lookup(&mut self, req: &Request, op: op::Lookup<'_>) -> Result<(), PolyfuseError> {
-        match self.fs.lookup(op.parent().into(), op.name()) {
+    fn handle_lookup(&mut self, req: &Request, op: op::Lookup<'_>) -> HandlerResult {
+        let _span = info_span!("lookup", parent = op.parent(), name = ?op.name()).entered();
+
+        let by_handle = self.export_support && op.name() == ".";
+
+        let result = match self.call(OpKind::Lookup, |fs| {
+            if by_handle {
+                let ino = op.parent().into();
+                let attrs = fs.getattr(ino)?;
+
+                Ok(Lookup::builder().attributes(attrs).inode(ino).build())
+            } else {
+                fs.lookup(op.parent().into(), op.name())
+            }
+        }) {
             Ok(obj) => {
                 let res = reply::EntryOut::from(obj);
 
                 req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("lookup error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
-        Ok(())
+        };
+        Ok(result)
     }
 
-    fn handle_getattr(&mut self, req: &Request, op: op::Getattr<'_>) -> Result<(), PolyfuseError> {
-        match self.fs.getattr(op.ino().into()) {
+    fn handle_getattr(&mut self, req: &Request, op: op::Getattr<'_>) -> HandlerResult {
+        let _span = info_span!("getattr", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Getattr, |fs| fs.getattr(op.ino().into())) {
             Ok(obj) => {
                 let mut conv: reply::AttrOut = reply::AttrOut::default();
 
@@ -309,45 +1484,195 @@ impl<T: Filesystem> Runner<T> {
                 obj.copy_attrs_to(op.ino().into(), conv.attr());
 
                 req.reply(conv).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("getattr error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
-        Ok(())
+        };
+        Ok(result)
     }
 
-    fn handle_setattr(&mut self, req: &Request, op: op::Setattr<'_>) -> Result<(), PolyfuseError> {
-        let to_duration = |spec: op::SetAttrTime| {
-            use op::SetAttrTime;
+    fn handle_access(&mut self, req: &Request, op: op::Access<'_>) -> HandlerResult {
+        let _span = info_span!("access", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Access, |fs| {
+            fs.access(
+                op.ino().into(),
+                req.uid(),
+                req.gid(),
+                AccessMask::from_libc_type(op.mask()),
+            )
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
 
-            match spec {
-                SetAttrTime::Timespec(dur) => Some(dur),
-                SetAttrTime::Now => Some(std::time::UNIX_EPOCH.elapsed().unwrap()),
-                spec => {
-                    error!(
-                        "Unknown timespec \"{:#?}\" encountered. Assuming `None` for now!",
-                        spec
-                    );
+                Ok(())
+            }
+            Err(e) => {
+                warn!("access error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
 
-                    None
-                }
+                Err(e)
             }
         };
 
-        let attrs = SetFileAttributes::builder()
-            .mode(op.mode())
-            .size(op.size())
-            .uid(op.uid())
-            .gid(op.gid())
-            .atime(op.atime().and_then(to_duration))
-            .mtime(op.mtime().and_then(to_duration))
-            .ctime(op.ctime())
-            .build();
+        Ok(result)
+    }
+
+    fn handle_statfs(&mut self, req: &Request, op: op::Statfs<'_>) -> HandlerResult {
+        let _span = info_span!("statfs", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Statfs, |fs| fs.statfs(op.ino().into())) {
+            Ok(obj) => {
+                let res = reply::StatfsOut::from(obj);
+                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("statfs error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_link(&mut self, req: &Request, op: op::Link<'_>) -> HandlerResult {
+        let _span = info_span!("link", ino = op.ino()).entered();
+
+        let result = match self.call(OpKind::Link, |fs| {
+            fs.link(op.ino().into(), op.newparent().into(), op.newname())
+        }) {
+            Ok(obj) => {
+                let res = reply::EntryOut::from(obj);
+                req.reply(res).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("link error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_unlink(&mut self, req: &Request, op: op::Unlink<'_>) -> HandlerResult {
+        let _span = info_span!("unlink", parent = op.parent(), name = ?op.name()).entered();
+
+        let result = match self.call(OpKind::Unlink, |fs| {
+            fs.unlink(op.parent().into(), op.name())
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("unlink error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_rmdir(&mut self, req: &Request, op: op::Rmdir<'_>) -> HandlerResult {
+        let _span = info_span!("rmdir", parent = op.parent(), name = ?op.name()).entered();
+
+        let result = match self.call(OpKind::Rmdir, |fs| fs.rmdir(op.parent().into(), op.name())) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("rmdir error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_rename(&mut self, req: &Request, op: op::Rename<'_>) -> HandlerResult {
+        let _span = info_span!("rename", parent = op.parent(), name = ?op.name(), new_parent = op.newparent()).entered();
+
+        let flags = match RenameFlags::from_libc_type(op.flags()) {
+            Some(flags) => flags,
+            None => {
+                let e = FSError::InvalidFlags(op.flags());
+
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                return Ok(Err(e));
+            }
+        };
 
-        match self.fs.setattr(op.ino().into(), attrs) {
+        let result = match self.call(OpKind::Rename, |fs| {
+            fs.rename(
+                op.parent().into(),
+                op.name(),
+                op.newparent().into(),
+                op.newname(),
+                flags,
+            )
+        }) {
+            Ok(()) => {
+                req.reply(()).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("rename error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
+    }
+
+    fn handle_setattr(&mut self, req: &Request, op: op::Setattr<'_>) -> HandlerResult {
+        let _span = info_span!("setattr", ino = op.ino()).entered();
+
+        let attrs = set_file_attributes(&op);
+
+        let result = match self.call(OpKind::Setattr, |fs| {
+            if attrs.is_noop() {
+                // nothing to change, so skip straight to returning the current attributes
+                fs.getattr(op.ino().into())
+            } else if let Some(size) = attrs.truncate_size() {
+                let fh = op.fh().map(Filehandle::from_raw);
+                fs.truncate(op.ino().into(), fh, size)
+            } else {
+                fs.setattr(op.ino().into(), attrs)
+            }
+        }) {
             Ok(obj) => {
                 let mut conv: reply::AttrOut = reply::AttrOut::default();
 
@@ -355,101 +1680,222 @@ impl<T: Filesystem> Runner<T> {
                 obj.copy_attrs_to(op.ino().into(), conv.attr());
 
                 req.reply(conv).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("setattr error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
-    fn handle_readdir(&mut self, req: &Request, op: op::Readdir<'_>) -> Result<(), PolyfuseError> {
-        // TODO implement readdir plus support
-        // readdirplus doesn't seem to be documented by polyfuse plus, so we just force it to error
-        // currently
+    fn handle_readdir(&mut self, req: &Request, op: op::Readdir<'_>) -> HandlerResult {
+        let _span = info_span!("readdir", ino = op.ino()).entered();
+
         if op.mode() == op::ReaddirMode::Plus {
-            req.reply_error(FSError::NotImplemented.to_libc_error())
-                .map_err(PolyfuseError::ReplyErrError)?;
-            return Ok(());
+            return self.handle_readdirplus(req, op);
         }
 
-        match self.fs.readdir(op.ino().into(), op.offset()) {
+        let result = match self.call(OpKind::Readdir, |fs| {
+            fs.readdir(op.ino().into(), op.offset())
+        }) {
             Ok(entries) => {
+                if cfg!(debug_assertions) && op.offset() == 0 {
+                    if let Err(msg) = check_readdir_entries(&entries) {
+                        warn!("readdir returned invalid entries for {}: {}", op.ino(), msg);
+                    }
+                }
+
                 let mut rep = reply::ReaddirOut::new(op.size() as usize);
 
-                // use take_while as a for_each_while
-                entries
-                    .into_iter()
-                    .take_while(|x| {
-                        rep.entry(
-                            &x.name,
-                            x.inode.to_u64(),
-                            x.typ.to_libc_type() as u32,
-                            x.offset,
-                        )
-                    })
-                    .for_each(|_| {});
+                for x in &entries {
+                    // returns true once the buffer is full
+                    if rep.entry(
+                        &x.name,
+                        x.inode.to_u64(),
+                        x.typ.to_libc_type() as u32,
+                        x.offset,
+                    ) {
+                        break;
+                    }
+                }
 
                 req.reply(rep).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("readdir error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
+    }
+
+    fn handle_readdirplus(&mut self, req: &Request, op: op::Readdir<'_>) -> HandlerResult {
+        let result = match self.call(OpKind::Readdir, |fs| {
+            fs.readdirplus(op.ino().into(), op.offset())
+        }) {
+            Ok(entries) => {
+                let mut rep = ReaddirPlusOut::new(op.size() as usize);
+
+                for entry in entries {
+                    if rep.entry(entry) {
+                        break;
+                    }
+                }
+
+                req.reply(rep.buf).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
+            }
+            Err(e) => {
+                warn!("readdirplus error occured: {:#?}", e);
+                req.reply_error(e.to_libc_error())
+                    .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
+            }
+        };
+
+        Ok(result)
     }
 
-    fn handle_read(&mut self, req: &Request, op: op::Read<'_>) -> Result<(), PolyfuseError> {
-        match self.fs.read(op.ino().into(), op.offset(), op.size()) {
+    fn handle_read(&mut self, req: &Request, op: op::Read<'_>) -> HandlerResult {
+        let _span = info_span!(
+            "read",
+            ino = op.ino(),
+            offset = op.offset(),
+            size = op.size()
+        )
+        .entered();
+
+        let result = match self.call(OpKind::Read, |fs| {
+            fs.pre_read(op.ino().into(), op.offset(), op.size());
+            fs.read(op.ino().into(), op.offset(), op.size())
+        }) {
             Ok(data) => {
                 req.reply(data).map_err(PolyfuseError::ReplyError)?;
+
+                Ok(())
             }
             Err(e) => {
                 warn!("read error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 
     fn handle_write<B: BufRead>(
         &mut self,
         req: &Request,
+        notifier: &Notifier,
         op: op::Write<'_>,
         buf: B,
-    ) -> Result<(), PolyfuseError> {
-        match self.fs.write(op.ino().into(), op.offset(), op.size(), buf) {
-            Ok(len) => {
+    ) -> HandlerResult {
+        let _span = info_span!(
+            "write",
+            ino = op.ino(),
+            offset = op.offset(),
+            size = op.size()
+        )
+        .entered();
+
+        // With writeback caching the kernel sends page writes at offsets it has already worked
+        // out, which must be honoured even for files opened with O_APPEND
+        let append = !self.writeback_cache && op.flags() & libc::O_APPEND as u32 != 0;
+
+        let written = self.call(OpKind::Write, |fs| {
+            let written = if append {
+                let fh = Filehandle::from_raw(op.fh());
+                fs.write_append(op.ino().into(), fh, op.size(), buf)?
+            } else {
+                fs.write(op.ino().into(), op.offset(), op.size(), buf)?
+            };
+
+            fs.post_write(
+                op.ino().into(),
+                op.offset(),
+                op.size(),
+                written.bytes_written(),
+            );
+
+            Ok(written)
+        });
+
+        let result = match written {
+            Ok(written) => {
+                let len = written.bytes_written();
+                debug_assert!(
+                    len > 0 || op.size() == 0,
+                    "write returned 0 bytes for a non-empty write"
+                );
+
                 let mut rep = reply::WriteOut::default();
                 rep.size(len);
 
                 req.reply(rep).map_err(PolyfuseError::ReplyError)?;
+
+                // Only notify once the write has been replied to, since the kernel may still be
+                // holding the inode lock until then
+                if written.invalidate_cache() {
+                    if let Err(e) = notifier.inval_inode(op.ino(), 0, 0) {
+                        warn!("failed to invalidate cache for {}: {}", op.ino(), e);
+                    }
+                }
+
+                Ok(())
             }
             Err(e) => {
                 warn!("write error occured: {:#?}", e);
                 req.reply_error(e.to_libc_error())
                     .map_err(PolyfuseError::ReplyErrError)?;
+
+                Err(e)
             }
-        }
+        };
 
-        Ok(())
+        Ok(result)
     }
 }
 
+/// The thread started by `Runner::run`, which hands the runner back along with how it ended
+pub type RunnerHandle<T> = JoinHandle<(Runner<T>, Result<()>)>;
+
 impl<T: Filesystem + Send + 'static> Runner<T> {
     /// Runs `self.run_block()` by spawning a new thread and returning the join handle.
-    pub fn run(mut self) -> JoinHandle<(Runner<T>, Result<()>)> {
+    pub fn run(mut self) -> RunnerHandle<T> {
         std::thread::spawn(move || {
             let result = self.run_block();
             (self, result)
         })
     }
+
+    /// Like `run`, but also returns a sender which shuts the filesystem down, see
+    /// `run_block_with_shutdown`.
+    pub fn run_with_shutdown(mut self) -> (RunnerHandle<T>, Sender<()>) {
+        let (sender, shutdown) = mpsc::channel();
+
+        let handle = std::thread::spawn(move || {
+            let result = self.run_block_with_shutdown(shutdown);
+            (self, result)
+        });
+
+        (handle, sender)
+    }
 }